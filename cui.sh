@@ -1,4 +1,4 @@
 cargo build
+python3 tools/ksymtab.py target/x86_64-ferrios/debug/ferrios
 cargo bootimage
 qemu-system-x86_64 -nographic -serial mon:stdio -drive format=raw,file=target/x86_64-ferrios/debug/bootimage-ferrios.bin
-
@@ -0,0 +1,161 @@
+use x86_64::PhysAddr;
+use super::{ read_u16, read_u32, read_u64, GenericAddress };
+
+/// FADT (Fixed ACPI Description Table)
+/// 電源管理のレジスタと DSDT の場所を持つ
+pub struct Fadt {
+    table: &'static [u8],
+}
+
+/// IA-PC Boot Architecture Flags: 8042 (PS/2 コントローラ) がある
+pub const BOOT_ARCH_8042: u16 = 1 << 1;
+/// IA-PC Boot Architecture Flags: VGA がない
+pub const BOOT_ARCH_VGA_NOT_PRESENT: u16 = 1 << 2;
+
+/// flags: RESET_REG が使える
+pub const RESET_REG_SUP: u32 = 1 << 10;
+/// flags: ハードウェアの電源管理がない (PM1 などのレジスタがない)
+pub const HW_REDUCED_ACPI: u32 = 1 << 20;
+
+/// 各フィールドのオフセット
+const FIRMWARE_CTRL: usize = 36;
+const DSDT: usize = 40;
+const SCI_INT: usize = 46;
+const SMI_CMD: usize = 48;
+const ACPI_ENABLE: usize = 52;
+const ACPI_DISABLE: usize = 53;
+const PM1A_EVT_BLK: usize = 56;
+const PM1B_EVT_BLK: usize = 60;
+const PM1A_CNT_BLK: usize = 64;
+const PM1B_CNT_BLK: usize = 68;
+const PM_TMR_BLK: usize = 76;
+const PM1_EVT_LEN: usize = 88;
+const PM1_CNT_LEN: usize = 89;
+const PM_TMR_LEN: usize = 91;
+const CENTURY: usize = 108;
+const IAPC_BOOT_ARCH: usize = 109;
+const FLAGS: usize = 112;
+const RESET_REG: usize = 116;
+const RESET_VALUE: usize = 128;
+const X_FIRMWARE_CTRL: usize = 132;
+const X_DSDT: usize = 140;
+
+impl Fadt {
+    pub fn get() -> Option<Self> {
+        let table = super::find_table(b"FACP")?;
+        // ACPI 1.0 の FADT は FLAGS までしかない
+        if table.len() < FLAGS + 4 {
+            return None;
+        }
+        Some(Fadt { table })
+    }
+
+    pub fn revision(&self) -> u8 {
+        self.table[8]
+    }
+
+    /// FACS の物理アドレス
+    pub fn firmware_ctrl(&self) -> Option<PhysAddr> {
+        self.field_u64(X_FIRMWARE_CTRL)
+            .filter(|&addr| addr != 0)
+            .or(Some(read_u32(self.table, FIRMWARE_CTRL) as u64))
+            .filter(|&addr| addr != 0)
+            .map(PhysAddr::new)
+    }
+
+    /// DSDT の物理アドレス
+    /// X_DSDT があればそちらを使う
+    pub fn dsdt_address(&self) -> Option<PhysAddr> {
+        self.field_u64(X_DSDT)
+            .filter(|&addr| addr != 0)
+            .or(Some(read_u32(self.table, DSDT) as u64))
+            .filter(|&addr| addr != 0)
+            .map(PhysAddr::new)
+    }
+
+    /// DSDT の中身 (ヘッダを含む)
+    pub fn dsdt(&self) -> Option<&'static [u8]> {
+        super::table_at(self.dsdt_address()?, b"DSDT")
+    }
+
+    /// SCI 割り込みの番号 (8259 モードでの IRQ)
+    pub fn sci_interrupt(&self) -> u16 {
+        read_u16(self.table, SCI_INT)
+    }
+
+    /// ACPI モードに切り替えるための SMI コマンドポート
+    /// 0 なら常に ACPI モード
+    pub fn smi_command_port(&self) -> u32 {
+        read_u32(self.table, SMI_CMD)
+    }
+
+    pub fn acpi_enable(&self) -> u8 {
+        self.table[ACPI_ENABLE]
+    }
+
+    pub fn acpi_disable(&self) -> u8 {
+        self.table[ACPI_DISABLE]
+    }
+
+    pub fn pm1a_event_block(&self) -> u32 {
+        read_u32(self.table, PM1A_EVT_BLK)
+    }
+
+    pub fn pm1b_event_block(&self) -> u32 {
+        read_u32(self.table, PM1B_EVT_BLK)
+    }
+
+    /// PM1a コントロールレジスタの I/O ポート
+    /// SLP_TYP と SLP_EN を書き込むと S5 (電源断) などに移る
+    pub fn pm1a_control_block(&self) -> u32 {
+        read_u32(self.table, PM1A_CNT_BLK)
+    }
+
+    pub fn pm1b_control_block(&self) -> u32 {
+        read_u32(self.table, PM1B_CNT_BLK)
+    }
+
+    pub fn pm1_event_length(&self) -> u8 {
+        self.table[PM1_EVT_LEN]
+    }
+
+    pub fn pm1_control_length(&self) -> u8 {
+        self.table[PM1_CNT_LEN]
+    }
+
+    /// ACPI PM タイマの I/O ポート (3.579545 MHz)
+    pub fn pm_timer_block(&self) -> Option<u32> {
+        let port = read_u32(self.table, PM_TMR_BLK);
+        (port != 0 && self.table[PM_TMR_LEN] == 4).then_some(port)
+    }
+
+    /// RTC の世紀のレジスタ番号
+    pub fn century(&self) -> Option<u8> {
+        Some(self.table[CENTURY]).filter(|&reg| reg != 0)
+    }
+
+    /// IA-PC Boot Architecture Flags (ACPI 2.0 以降)
+    pub fn boot_arch_flags(&self) -> u16 {
+        if self.revision() >= 2 {
+            read_u16(self.table, IAPC_BOOT_ARCH)
+        } else {
+            0
+        }
+    }
+
+    pub fn flags(&self) -> u32 {
+        read_u32(self.table, FLAGS)
+    }
+
+    /// リセットレジスタと書き込む値
+    pub fn reset_register(&self) -> Option<(GenericAddress, u8)> {
+        if self.flags() & RESET_REG_SUP == 0 || self.table.len() < RESET_VALUE + 1 {
+            return None;
+        }
+        Some((GenericAddress::parse(self.table, RESET_REG), self.table[RESET_VALUE]))
+    }
+
+    fn field_u64(&self, offset: usize) -> Option<u64> {
+        (self.table.len() >= offset + 8).then(|| read_u64(self.table, offset))
+    }
+}
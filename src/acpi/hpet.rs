@@ -0,0 +1,69 @@
+use super::{ read_u16, read_u32, GenericAddress, GAS_SIZE };
+
+/// HPET (High Precision Event Timer) のテーブル
+pub struct Hpet {
+    table: &'static [u8],
+}
+
+/// 各フィールドのオフセット
+const EVENT_TIMER_BLOCK_ID: usize = 36;
+const BASE_ADDRESS: usize = 40;
+const HPET_NUMBER: usize = 40 + GAS_SIZE;
+const MINIMUM_TICK: usize = HPET_NUMBER + 1;
+const PAGE_PROTECTION: usize = MINIMUM_TICK + 2;
+
+impl Hpet {
+    pub fn get() -> Option<Self> {
+        let table = super::find_table(b"HPET")?;
+        if table.len() < PAGE_PROTECTION + 1 {
+            return None;
+        }
+        Some(Hpet { table })
+    }
+
+    /// レジスタの場所 (通常は SystemMemory)
+    pub fn base_address(&self) -> GenericAddress {
+        GenericAddress::parse(self.table, BASE_ADDRESS)
+    }
+
+    /// ハードウェアのリビジョン
+    pub fn hardware_revision(&self) -> u8 {
+        self.event_timer_block_id() as u8
+    }
+
+    /// コンパレータ (タイマ) の数
+    pub fn comparator_count(&self) -> u8 {
+        ((self.event_timer_block_id() >> 8) & 0x1f) as u8 + 1
+    }
+
+    /// カウンタが 64 ビットか
+    pub fn counter_is_64bit(&self) -> bool {
+        self.event_timer_block_id() & (1 << 13) != 0
+    }
+
+    /// レガシー置き換え (IRQ0 / IRQ8 の代わり) ができるか
+    pub fn legacy_replacement_capable(&self) -> bool {
+        self.event_timer_block_id() & (1 << 15) != 0
+    }
+
+    pub fn pci_vendor_id(&self) -> u16 {
+        (self.event_timer_block_id() >> 16) as u16
+    }
+
+    pub fn hpet_number(&self) -> u8 {
+        self.table[HPET_NUMBER]
+    }
+
+    /// 周期モードで使える最小のクロック数
+    pub fn minimum_tick(&self) -> u16 {
+        read_u16(self.table, MINIMUM_TICK)
+    }
+
+    pub fn page_protection(&self) -> u8 {
+        self.table[PAGE_PROTECTION]
+    }
+
+    fn event_timer_block_id(&self) -> u32 {
+        read_u32(self.table, EVENT_TIMER_BLOCK_ID)
+    }
+}
@@ -0,0 +1,109 @@
+use super::{ read_u16, read_u32, read_u64, SDT_HEADER_SIZE };
+
+/// MADT (Multiple APIC Description Table)
+pub struct Madt {
+    table: &'static [u8],
+}
+
+/// MADT のエントリ
+#[derive(Debug, Clone, Copy)]
+pub enum MadtEntry {
+    /// プロセッサの Local APIC
+    LocalApic { processor_id: u8, apic_id: u8, flags: u32 },
+    IoApic { id: u8, address: u32, gsi_base: u32 },
+    /// ISA の IRQ を別の GSI につなぎ替える
+    InterruptSourceOverride { bus: u8, source: u8, gsi: u32, flags: u16 },
+    LocalApicNmi { processor_id: u8, flags: u16, lint: u8 },
+    LocalApicAddressOverride { address: u64 },
+    Unknown { entry_type: u8 },
+}
+
+/// LocalApic の flags: プロセッサが使える
+pub const LOCAL_APIC_ENABLED: u32 = 1 << 0;
+/// LocalApic の flags: OS が有効にできる (ENABLED でなくても起動できる)
+pub const LOCAL_APIC_ONLINE_CAPABLE: u32 = 1 << 1;
+
+/// MADT の flags: 8259 も載っている
+pub const PCAT_COMPAT: u32 = 1 << 0;
+
+/// InterruptSourceOverride などの flags (MPS INTI flags)
+pub const POLARITY_MASK: u16 = 0b11;
+pub const POLARITY_ACTIVE_LOW: u16 = 0b11;
+pub const TRIGGER_MASK: u16 = 0b11 << 2;
+pub const TRIGGER_LEVEL: u16 = 0b11 << 2;
+
+impl Madt {
+    pub fn get() -> Option<Self> {
+        let table = super::find_table(b"APIC")?;
+        if table.len() < SDT_HEADER_SIZE + 8 {
+            return None;
+        }
+        Some(Madt { table })
+    }
+
+    /// Local APIC のレジスタの物理アドレス
+    pub fn local_apic_address(&self) -> u64 {
+        self.entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(read_u32(self.table, SDT_HEADER_SIZE) as u64)
+    }
+
+    pub fn flags(&self) -> u32 {
+        read_u32(self.table, SDT_HEADER_SIZE + 4)
+    }
+
+    /// 起動できるプロセッサの APIC ID
+    pub fn apic_ids(&self) -> impl Iterator<Item = u8> + 'static {
+        self.entries().filter_map(|entry| match entry {
+            MadtEntry::LocalApic { apic_id, flags, .. }
+                if flags & (LOCAL_APIC_ENABLED | LOCAL_APIC_ONLINE_CAPABLE) != 0 => Some(apic_id),
+            _ => None,
+        })
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = MadtEntry> + 'static {
+        let table = self.table;
+        let mut offset = SDT_HEADER_SIZE + 8;
+        core::iter::from_fn(move || {
+            // 各エントリは type, length の 2 バイトから始まる
+            let header = table.get(offset..offset + 2)?;
+            let (entry_type, length) = (header[0], header[1] as usize);
+            let entry = table.get(offset..offset + length).filter(|_| length >= 2)?;
+            offset += length;
+            Some(parse_entry(entry_type, entry))
+        })
+    }
+}
+
+fn parse_entry(entry_type: u8, entry: &[u8]) -> MadtEntry {
+    match (entry_type, entry.len()) {
+        (0, 8..) => MadtEntry::LocalApic {
+            processor_id: entry[2],
+            apic_id: entry[3],
+            flags: read_u32(entry, 4),
+        },
+        (1, 12..) => MadtEntry::IoApic {
+            id: entry[2],
+            address: read_u32(entry, 4),
+            gsi_base: read_u32(entry, 8),
+        },
+        (2, 10..) => MadtEntry::InterruptSourceOverride {
+            bus: entry[2],
+            source: entry[3],
+            gsi: read_u32(entry, 4),
+            flags: read_u16(entry, 8),
+        },
+        (4, 6..) => MadtEntry::LocalApicNmi {
+            processor_id: entry[2],
+            flags: read_u16(entry, 3),
+            lint: entry[5],
+        },
+        (5, 12..) => MadtEntry::LocalApicAddressOverride {
+            address: read_u64(entry, 4),
+        },
+        _ => MadtEntry::Unknown { entry_type },
+    }
+}
@@ -0,0 +1,228 @@
+use conquer_once::spin::OnceCell;
+use x86_64::PhysAddr;
+use crate::memory;
+
+pub mod madt;
+pub mod fadt;
+pub mod hpet;
+
+/// RSDP のシグネチャ
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+/// BIOS 領域 (EBDA の先頭 1 KiB と 0xE0000 ~ 0xFFFFF) で RSDP を探す
+const EBDA_SEGMENT_PTR: u64 = 0x40E;
+const EBDA_SEARCH_SIZE: u64 = 1024;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+/// テーブルの共通ヘッダの大きさ
+pub const SDT_HEADER_SIZE: usize = 36;
+
+/// テーブルの共通ヘッダ
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+}
+
+impl SdtHeader {
+    fn parse(table: &[u8]) -> Self {
+        SdtHeader {
+            signature: table[0..4].try_into().unwrap(),
+            length: read_u32(table, 4),
+            revision: table[8],
+            oem_id: table[10..16].try_into().unwrap(),
+            oem_table_id: table[16..24].try_into().unwrap(),
+            oem_revision: read_u32(table, 24),
+        }
+    }
+
+    pub fn signature_str(&self) -> &str {
+        core::str::from_utf8(&self.signature).unwrap_or("????")
+    }
+
+    pub fn oem_id_str(&self) -> &str {
+        core::str::from_utf8(&self.oem_id).unwrap_or("").trim_end()
+    }
+}
+
+/// Generic Address Structure
+/// FADT や HPET がレジスタの場所を示すのに使う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GenericAddress {
+    pub address_space: AddressSpace,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// GenericAddress のアドレス空間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressSpace {
+    SystemMemory,
+    SystemIo,
+    PciConfig,
+    Other(u8),
+}
+
+/// Generic Address Structure の大きさ
+pub const GAS_SIZE: usize = 12;
+
+impl GenericAddress {
+    pub(crate) fn parse(bytes: &[u8], offset: usize) -> Self {
+        let bytes = &bytes[offset..offset + GAS_SIZE];
+        GenericAddress {
+            address_space: match bytes[0] {
+                0 => AddressSpace::SystemMemory,
+                1 => AddressSpace::SystemIo,
+                2 => AddressSpace::PciConfig,
+                other => AddressSpace::Other(other),
+            },
+            bit_width: bytes[1],
+            bit_offset: bytes[2],
+            access_size: bytes[3],
+            address: read_u64(bytes, 4),
+        }
+    }
+}
+
+/// RSDT / XSDT
+struct RootTable {
+    table: &'static [u8],
+    /// XSDT なら 8 バイト、RSDT なら 4 バイトのポインタが並ぶ
+    entry_size: usize,
+}
+
+static ROOT_TABLE: OnceCell<Option<RootTable>> = OnceCell::uninit();
+
+/// RSDP を探し、RSDT (ACPI 2.0 以降なら XSDT) を読む
+/// 物理メモリのマップを使うので、メモリの初期化後に呼ぶ
+pub fn init() {
+    ROOT_TABLE.init_once(|| {
+        let rsdp = find_rsdp()?;
+        let revision = rsdp[15];
+        if revision >= 2 {
+            let xsdt = read_u64(rsdp, 24);
+            if let Some(table) = table_at(PhysAddr::new(xsdt), b"XSDT") {
+                return Some(RootTable { table, entry_size: 8 });
+            }
+        }
+        let rsdt = read_u32(rsdp, 16) as u64;
+        table_at(PhysAddr::new(rsdt), b"RSDT").map(|table| RootTable { table, entry_size: 4 })
+    });
+}
+
+/// ACPI のテーブルが見つかったか
+pub fn is_available() -> bool {
+    matches!(ROOT_TABLE.get(), Some(Some(_)))
+}
+
+/// RSDT / XSDT に並ぶテーブルの物理アドレス
+fn table_addresses() -> impl Iterator<Item = PhysAddr> {
+    let root = ROOT_TABLE.get().and_then(|root| root.as_ref());
+    let entries = root.map(|root| (&root.table[SDT_HEADER_SIZE..], root.entry_size));
+    entries.into_iter().flat_map(|(entries, entry_size)| {
+        entries.chunks_exact(entry_size).map(move |entry| match entry_size {
+            8 => PhysAddr::new(read_u64(entry, 0)),
+            _ => PhysAddr::new(read_u32(entry, 0) as u64),
+        })
+    })
+}
+
+/// RSDT / XSDT に並ぶテーブルのヘッダ
+/// チェックサムが合わないテーブルは含めない
+pub fn tables() -> impl Iterator<Item = SdtHeader> {
+    table_addresses()
+        .filter_map(|addr| checked_table_at(addr, None))
+        .map(SdtHeader::parse)
+}
+
+/// signature のテーブルを探す
+/// チェックサムが合わないテーブルは無視する
+pub fn find_table(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    table_addresses().find_map(|addr| table_at(addr, signature))
+}
+
+/// 物理アドレス addr のテーブルのシグネチャとチェックサムを確かめて返す
+pub(crate) fn table_at(addr: PhysAddr, signature: &[u8; 4]) -> Option<&'static [u8]> {
+    checked_table_at(addr, Some(signature))
+}
+
+fn checked_table_at(addr: PhysAddr, signature: Option<&[u8; 4]>) -> Option<&'static [u8]> {
+    if addr.as_u64() == 0 {
+        return None;
+    }
+    let header = phys_bytes(addr, SDT_HEADER_SIZE);
+    if signature.is_some_and(|signature| &header[0..4] != signature) {
+        return None;
+    }
+    let length = read_u32(header, 4) as usize;
+    if length < SDT_HEADER_SIZE {
+        return None;
+    }
+    let table = phys_bytes(addr, length);
+    checksum_ok(table).then_some(table)
+}
+
+/// BIOS 領域から RSDP を探す
+fn find_rsdp() -> Option<&'static [u8]> {
+    let ebda = (read_u16(phys_bytes(PhysAddr::new(EBDA_SEGMENT_PTR), 2), 0) as u64) << 4;
+    let areas = [(ebda, ebda + EBDA_SEARCH_SIZE), (BIOS_AREA_START, BIOS_AREA_END)];
+    areas.into_iter()
+        .filter(|&(start, _)| start != 0)
+        .flat_map(|(start, end)| (start..end).step_by(16))
+        .find_map(|addr| {
+            let rsdp = phys_bytes(PhysAddr::new(addr), 20);
+            if &rsdp[0..8] != RSDP_SIGNATURE || !checksum_ok(rsdp) {
+                return None;
+            }
+            // ACPI 2.0 以降は 36 バイトに拡張されている
+            if rsdp[15] >= 2 {
+                let rsdp = phys_bytes(PhysAddr::new(addr), 36);
+                return checksum_ok(rsdp).then_some(rsdp);
+            }
+            Some(rsdp)
+        })
+}
+
+/// 全バイトの和が 0 になるか
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// 物理メモリのマップを通して [addr, addr + len) を読む
+fn phys_bytes(addr: PhysAddr, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(memory::phys_to_virt(addr).as_ptr(), len) }
+}
+
+pub(crate) fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+pub(crate) fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+pub(crate) fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[test_case]
+fn test_checksum() {
+    assert!(checksum_ok(&[0x01, 0xff]));
+    assert!(!checksum_ok(&[0x01, 0xfe]));
+}
+
+#[test_case]
+fn test_generic_address() {
+    let bytes = [1, 8, 0, 1, 0x04, 0x06, 0, 0, 0, 0, 0, 0];
+    let gas = GenericAddress::parse(&bytes, 0);
+    assert_eq!(gas.address_space, AddressSpace::SystemIo);
+    assert_eq!(gas.bit_width, 8);
+    assert_eq!(gas.address, 0x604);
+}
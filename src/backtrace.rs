@@ -0,0 +1,119 @@
+use core::arch::asm;
+use x86_64::VirtAddr;
+use crate::{ cpu, memory, println, symbols };
+use crate::thread::{ THREAD_TABLE, STACK_SIZE };
+
+/// 表示する最大フレーム数
+const MAX_FRAMES: usize = 64;
+
+/// フレームを読んでよいスタックの範囲
+enum StackBounds {
+    /// 実行中のスレッドのカーネルスタック [bottom, top)
+    Range(u64, u64),
+    /// スレッド外（ブート時のスタックなど）では、マップされているかだけを確かめる
+    Mapped,
+}
+
+impl StackBounds {
+    /// 実行中のスレッドのカーネルスタックを調べる
+    /// パニック中はロックを握ったままのことがあるので、try_lock で読む
+    fn current() -> Self {
+        let kstack = cpu::is_initialized()
+            .then(cpu::current_tid)
+            .flatten()
+            .and_then(|tid| THREAD_TABLE.try_lock().map(|table| table[tid].kstack));
+        match kstack {
+            Some(top) if top != 0 => StackBounds::Range(top - STACK_SIZE as u64, top),
+            _ => StackBounds::Mapped,
+        }
+    }
+
+    /// rbp が指す保存済み rbp と戻りアドレスの 16 バイトを読めるか
+    fn contains(&self, rbp: u64) -> bool {
+        if rbp == 0 || !rbp.is_multiple_of(8) {
+            return false;
+        }
+        match *self {
+            StackBounds::Range(bottom, top) => rbp >= bottom && rbp.checked_add(16).is_some_and(|end| end <= top),
+            StackBounds::Mapped => {
+                VirtAddr::try_new(rbp).is_ok_and(memory::is_mapped)
+                    && VirtAddr::try_new(rbp + 8).is_ok_and(memory::is_mapped)
+            }
+        }
+    }
+}
+
+/// 現在の rbp を返す
+#[inline(always)]
+pub fn frame_pointer() -> u64 {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    rbp
+}
+
+/// rbp から始まるフレームポインタの連鎖をたどり、戻りアドレスごとに f を呼ぶ
+/// スタックの範囲外に出るか、フレームが上位アドレスへ進まなくなったら止まる
+pub fn walk(mut rbp: u64, mut f: impl FnMut(u64)) {
+    let bounds = StackBounds::current();
+
+    for _ in 0..MAX_FRAMES {
+        if !bounds.contains(rbp) {
+            return;
+        }
+        // [rbp] に呼び出し元の rbp、[rbp + 8] に戻りアドレスが積まれている
+        let (next, return_address) = unsafe {
+            let frame = rbp as *const u64;
+            (*frame, *frame.add(1))
+        };
+        if return_address == 0 {
+            return;
+        }
+        f(return_address);
+
+        // スタックは下位アドレスへ伸びるので、呼び出し元のフレームは必ず上にある
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+/// 呼び出し元からのバックトレースを表示する
+#[inline(never)]
+pub fn print() {
+    println!("Backtrace:");
+    let mut depth = 0;
+    walk(frame_pointer(), |address| {
+        print_frame(depth, address);
+        depth += 1;
+    });
+}
+
+/// 例外が発生した時点の rip と rbp からバックトレースを表示する
+pub fn print_from(rip: u64, rbp: u64) {
+    println!("Backtrace:");
+    print_frame(0, rip);
+    let mut depth = 1;
+    walk(rbp, |address| {
+        print_frame(depth, address);
+        depth += 1;
+    });
+}
+
+/// フレームを1行表示する
+/// シンボルテーブルが埋め込まれていれば関数名も表示する
+fn print_frame(depth: usize, address: u64) {
+    match symbols::resolve(address) {
+        Some((name, offset)) => println!("  #{:<2} {:#018x} {}+{:#x}", depth, address, name, offset),
+        None => println!("  #{:<2} {:#018x}", depth, address),
+    }
+}
+
+#[test_case]
+fn test_walk_current_stack() {
+    let mut frames = 0;
+    walk(frame_pointer(), |_| frames += 1);
+    assert!(frames > 0);
+}
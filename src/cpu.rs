@@ -1,22 +1,239 @@
-use crate::scheduler::context;
-use lazy_static::lazy_static;
+use core::arch::asm;
+use core::mem::offset_of;
+use core::sync::atomic::{ AtomicU32, AtomicUsize, Ordering };
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{ GsBase, KernelGsBase };
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::scheduler::context::Context;
 
-lazy_static! {
-    pub static ref CPU: spin::Mutex<Cpu> = spin::Mutex::new(Cpu::new(0));
+/// 最大 CPU 数
+pub const MAX_CPUS: usize = 8;
+
+/// CPU ごとのデータ
+/// カーネルモードでは GS ベースがこの CPU の PerCpu を指す
+/// その CPU だけが読み書きするので、ロックせずに gs 相対で 1 命令ずつ読み書きする
+#[repr(C)]
+pub struct PerCpu {
+    this: *mut PerCpu,          // この PerCpu 自身のアドレス
+    kernel_rsp: u64,            // syscall で切り替えるカーネルスタック (TSS.RSP0 と同じ値)
+    user_rsp: u64,              // syscall でカーネルスタックに切り替えるまでユーザの RSP を退避する
+    id: usize,                  // CPU ID
+    current_tid: usize,         // 現在実行中のスレッド ID (NO_THREAD なら None)
+    preempt_count: usize,       // 0 でなければタイマ割り込みでプリエンプトしない
+    started: bool,              // スケジューラを開始したか
+    scheduler: Context,         // スケジューラ用コンテキスト
+}
+
+/// current_tid がスレッドを指していないときの値
+const NO_THREAD: usize = usize::MAX;
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            this: core::ptr::null_mut(),
+            kernel_rsp: 0,
+            user_rsp: 0,
+            id: 0,
+            current_tid: NO_THREAD,
+            preempt_count: 0,
+            started: false,
+            scheduler: Context::new(),
+        }
+    }
+}
+
+/// syscall のエントリスタブが使う PerCpu のオフセット
+pub const KERNEL_RSP_OFFSET: usize = offset_of!(PerCpu, kernel_rsp);
+pub const USER_RSP_OFFSET: usize = offset_of!(PerCpu, user_rsp);
+
+static mut PER_CPU: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+
+/// CPU ID (PER_CPU の添字) ごとの APIC ID
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(u32::MAX) }; MAX_CPUS];
+
+/// 登録済みの CPU 数
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// この CPU の GS ベースを cpu_id の PerCpu に設定する
+/// この CPU で最初に、割り込みを禁止した状態で呼ぶ
+pub fn init(cpu_id: usize) {
+    assert!(cpu_id < MAX_CPUS);
+    unsafe {
+        let per_cpu = &raw mut PER_CPU[cpu_id];
+        (*per_cpu).this = per_cpu;
+        (*per_cpu).id = cpu_id;
+        GsBase::write(VirtAddr::from_ptr(per_cpu));
+        // ユーザモードでの GS ベース (swapgs で入れ替わる)
+        KernelGsBase::write(VirtAddr::zero());
+    }
+}
+
+/// この CPU で init を呼んだか
+/// パニックや例外の表示など、初期化前にも呼ばれる場所で確かめる
+pub fn is_initialized() -> bool {
+    GsBase::read().as_u64() != 0
+}
+
+/// APIC ID の CPU を登録し、CPU ID を返す
+/// 最初に登録した CPU (BSP) が CPU 0 になる
+pub fn register(apic_id: u8) -> Option<usize> {
+    let id = CPU_COUNT.load(Ordering::Acquire);
+    if id >= MAX_CPUS {
+        return None;
+    }
+    APIC_IDS[id].store(apic_id as u32, Ordering::Relaxed);
+    CPU_COUNT.store(id + 1, Ordering::Release);
+    Some(id)
 }
 
-pub struct Cpu {
-    pub id: usize,                      // CPU ID
-    pub scheduler: context::Context,    // スケジューラ用コンテキスト
-    pub current_tid: Option<usize>,     // 現在実行中のスレッド ID
+/// 起動できなかった CPU の登録を取り消す
+/// 最後に登録した CPU だけを取り消せる
+pub fn unregister(id: usize) {
+    if CPU_COUNT.compare_exchange(id + 1, id, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+        APIC_IDS[id].store(u32::MAX, Ordering::Relaxed);
+    }
 }
 
-impl Cpu {
-    pub fn new(cpu_id: usize) -> Self {
-        Cpu {
-            id: cpu_id,
-            scheduler: context::Context::new(),
-            current_tid: None,
+/// 登録済みの CPU 数
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire).max(1)
+}
+
+/// 登録済みのすべての CPU のビットを立てたマスク (ビット i が CPU ID i)
+pub fn all_cpus_mask() -> u64 {
+    (1u64 << count()) - 1
+}
+
+/// 実行中の CPU の CPU ID
+pub fn id() -> usize {
+    read(offset_of!(PerCpu, id)) as usize
+}
+
+/// 実行中のスレッドの tid
+/// 別の CPU に移っても自分の tid は変わらないので、割り込みが有効でも正しく読める
+pub fn current_tid() -> Option<usize> {
+    let tid = read(offset_of!(PerCpu, current_tid)) as usize;
+    (tid != NO_THREAD).then_some(tid)
+}
+
+/// 実行中のスレッドの tid を設定する
+/// スケジューラから、割り込みを禁止した状態で呼ぶ
+pub fn set_current_tid(tid: Option<usize>) {
+    write(offset_of!(PerCpu, current_tid), tid.unwrap_or(NO_THREAD) as u64);
+}
+
+/// 実行中のスレッドの tid を取り出し、None にする
+/// スケジューラから、割り込みを禁止した状態で呼ぶ
+pub fn take_current_tid() -> Option<usize> {
+    let tid = current_tid();
+    set_current_tid(None);
+    tid
+}
+
+/// スケジューラ用コンテキスト
+/// スケジューラとの切り替えは割り込みを禁止して行うので、その間は別の CPU に移らない
+pub fn scheduler_context() -> *mut Context {
+    (read(offset_of!(PerCpu, this)) + offset_of!(PerCpu, scheduler) as u64) as *mut Context
+}
+
+/// この CPU でスケジューラを開始したことを記録する
+/// すでに開始していれば false を返す
+pub fn start_scheduler() -> bool {
+    let started = read_u8(offset_of!(PerCpu, started)) != 0;
+    write_u8(offset_of!(PerCpu, started), 1);
+    !started
+}
+
+/// syscall で切り替えるカーネルスタックを設定する
+pub fn set_kernel_rsp(stack_top: u64) {
+    write(offset_of!(PerCpu, kernel_rsp), stack_top);
+}
+
+/// プリエンプトを禁止する
+/// preempt_enable と対にして呼ぶ
+pub fn preempt_disable() {
+    unsafe {
+        asm!("inc qword ptr gs:[{}]", in(reg) offset_of!(PerCpu, preempt_count), options(nostack));
+    }
+}
+
+/// preempt_disable で禁止したプリエンプトを許可する
+pub fn preempt_enable() {
+    unsafe {
+        asm!("dec qword ptr gs:[{}]", in(reg) offset_of!(PerCpu, preempt_count), options(nostack));
+    }
+}
+
+/// 0 でなければタイマ割り込みでプリエンプトしない
+pub fn preempt_count() -> usize {
+    read(offset_of!(PerCpu, preempt_count)) as usize
+}
+
+/// ユーザモードから割り込み・例外で入ったときに、GS ベースをこの CPU の PerCpu に切り替える
+/// ドロップすると GS ベースをユーザモードのものに戻すので、ハンドラの最初に作り、ユーザモードに戻る直前にドロップさせる
+pub struct KernelGs {
+    swapped: bool,
+}
+
+impl KernelGs {
+    pub fn enter(stack_frame: &InterruptStackFrame) -> Self {
+        // CS の下位2ビットが CPL（現在の特権レベル）
+        let swapped = stack_frame.code_segment & 0b11 == 3;
+        if swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
+        }
+        KernelGs { swapped }
+    }
+}
+
+impl Drop for KernelGs {
+    fn drop(&mut self) {
+        if self.swapped {
+            unsafe { asm!("swapgs", options(nostack, preserves_flags)) };
         }
     }
 }
+
+fn read(offset: usize) -> u64 {
+    let value: u64;
+    unsafe {
+        asm!("mov {}, gs:[{}]", out(reg) value, in(reg) offset, options(nostack, readonly, preserves_flags));
+    }
+    value
+}
+
+fn write(offset: usize, value: u64) {
+    unsafe {
+        asm!("mov gs:[{}], {}", in(reg) offset, in(reg) value, options(nostack, preserves_flags));
+    }
+}
+
+fn read_u8(offset: usize) -> u8 {
+    let value: u8;
+    unsafe {
+        asm!("mov {}, gs:[{}]", out(reg_byte) value, in(reg) offset, options(nostack, readonly, preserves_flags));
+    }
+    value
+}
+
+fn write_u8(offset: usize, value: u8) {
+    unsafe {
+        asm!("mov gs:[{}], {}", in(reg) offset, in(reg_byte) value, options(nostack, preserves_flags));
+    }
+}
+
+#[test_case]
+fn test_per_cpu_without_apic() {
+    // テストでは AP を起動しないので、常に BSP
+    assert_eq!(id(), 0);
+    assert_eq!(current_tid(), None);
+}
+
+#[test_case]
+fn test_preempt_count() {
+    let before = preempt_count();
+    preempt_disable();
+    assert_eq!(preempt_count(), before + 1);
+    preempt_enable();
+    assert_eq!(preempt_count(), before);
+}
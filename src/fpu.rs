@@ -0,0 +1,162 @@
+use core::arch::asm;
+use core::arch::x86_64::{ __cpuid, __cpuid_count };
+use core::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use conquer_once::spin::OnceCell;
+use x86_64::registers::control::{ Cr0, Cr0Flags, Cr4, Cr4Flags };
+use x86_64::registers::xcontrol::{ XCr0, XCr0Flags };
+
+/// FPU/SSE/AVX の状態を保存する領域の大きさ
+/// x87・SSE・AVX の XSAVE 領域 (832 バイト) が収まる大きさにする
+pub const STATE_SIZE: usize = 1024;
+
+/// XSAVE は 64 バイト境界の領域を要求する
+const STATE_ALIGN: usize = 64;
+
+/// 保存領域
+#[repr(C, align(64))]
+struct FpuArea([u8; STATE_SIZE]);
+
+/// XSAVE を使うか (false なら FXSAVE)
+static XSAVE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// XSAVE で保存する状態 (XCR0)
+static XSAVE_MASK: AtomicU64 = AtomicU64::new(0);
+
+/// 新しいスレッドの FPU の初期状態
+static INITIAL_STATE: OnceCell<FpuArea> = OnceCell::uninit();
+
+/// この CPU で FPU・SSE を有効にし、XSAVE があれば AVX まで有効にする
+/// 各 CPU で、スレッドを実行する前に呼ぶ
+/// カーネル自身は浮動小数点を使わず、スレッドの状態はコンテキストスイッチで保存・復元する
+pub fn init() {
+    let xsave_supported = __cpuid(1).ecx & (1 << 26) != 0;
+    let avx_supported = __cpuid(1).ecx & (1 << 28) != 0;
+
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR | Cr0Flags::TASK_SWITCHED);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| {
+            flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+            if xsave_supported {
+                flags.insert(Cr4Flags::OSXSAVE);
+            }
+        });
+    }
+
+    if xsave_supported {
+        let mut mask = XCr0Flags::X87 | XCr0Flags::SSE;
+        if avx_supported {
+            mask |= XCr0Flags::AVX;
+        }
+        unsafe { XCr0::write(mask) };
+
+        // 有効にした状態の XSAVE 領域の大きさ
+        let size = __cpuid_count(0xD, 0).ebx as usize;
+        assert!(size <= STATE_SIZE, "XSAVE area too large ({} bytes)", size);
+        XSAVE_MASK.store(mask.bits(), Ordering::Relaxed);
+    }
+    XSAVE_ENABLED.store(xsave_supported, Ordering::Relaxed);
+
+    unsafe { asm!("fninit", options(nomem, nostack)) };
+
+    // 最初に初期化した CPU の状態を、新しいスレッドの初期状態にする
+    INITIAL_STATE.init_once(|| {
+        let mut area = FpuArea([0; STATE_SIZE]);
+        save(area.0.as_mut_ptr() as u64);
+        area
+    });
+}
+
+/// 初期状態の保存領域を確保し、そのアドレスを返す
+/// ヒープが足りなければエラーを返す
+pub fn alloc_state() -> Result<u64, &'static str> {
+    let layout = alloc::alloc::Layout::from_size_align(STATE_SIZE, STATE_ALIGN).unwrap();
+    let area = unsafe { alloc::alloc::alloc(layout) };
+    if area.is_null() {
+        return Err("FPU state allocation failed");
+    }
+    copy_initial_state(area as u64);
+    Ok(area as u64)
+}
+
+/// alloc_state で確保した保存領域を解放する
+pub fn free_state(area: u64) {
+    let layout = alloc::alloc::Layout::from_size_align(STATE_SIZE, STATE_ALIGN).unwrap();
+    unsafe {
+        alloc::alloc::dealloc(area as *mut u8, layout);
+    }
+}
+
+/// 現在の FPU/SSE/AVX のレジスタを area に保存する
+pub fn save(area: u64) {
+    unsafe {
+        if XSAVE_ENABLED.load(Ordering::Relaxed) {
+            let mask = XSAVE_MASK.load(Ordering::Relaxed);
+            asm!("xsave64 [{}]", in(reg) area, in("eax") mask as u32, in("edx") (mask >> 32) as u32, options(nostack));
+        } else {
+            asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+        }
+    }
+}
+
+/// area に保存した状態を FPU/SSE/AVX のレジスタに戻す
+pub fn restore(area: u64) {
+    unsafe {
+        if XSAVE_ENABLED.load(Ordering::Relaxed) {
+            let mask = XSAVE_MASK.load(Ordering::Relaxed);
+            asm!("xrstor64 [{}]", in(reg) area, in("eax") mask as u32, in("edx") (mask >> 32) as u32, options(nostack, readonly));
+        } else {
+            asm!("fxrstor64 [{}]", in(reg) area, options(nostack, readonly));
+        }
+    }
+}
+
+/// area を初期状態に戻し、レジスタにも読み込む
+/// exec で実行中のスレッドの状態を捨てるときに使う
+/// コピーと復元の間に切り替わると古いレジスタで上書きされるので、割り込みを禁止して行う
+pub fn reset(area: u64) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        copy_initial_state(area);
+        restore(area);
+    });
+}
+
+fn copy_initial_state(area: u64) {
+    let initial = INITIAL_STATE.get().expect("FPU not initialized");
+    unsafe {
+        core::ptr::copy_nonoverlapping(initial.0.as_ptr(), area as *mut u8, STATE_SIZE);
+    }
+}
+
+#[cfg(test)]
+fn mxcsr() -> u32 {
+    let mut value = 0u32;
+    unsafe { asm!("stmxcsr [{}]", in(reg) &mut value, options(nostack)) };
+    value
+}
+
+#[cfg(test)]
+fn set_mxcsr(value: u32) {
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &value, options(nostack, readonly)) };
+}
+
+#[test_case]
+fn test_save_and_restore() {
+    // テストではヒープを初期化しないので、スタック上の領域を使う
+    let mut buffer = FpuArea([0; STATE_SIZE]);
+    let area = buffer.0.as_mut_ptr() as u64;
+    copy_initial_state(area);
+    let original = mxcsr();
+
+    // 丸めモードを変えて保存し、初期状態に戻してから復元する
+    set_mxcsr(original | 0x6000);
+    save(area);
+    set_mxcsr(original);
+    restore(area);
+    assert_eq!(mxcsr(), original | 0x6000);
+
+    reset(area);
+    assert_eq!(mxcsr(), original);
+}
@@ -3,62 +3,89 @@ use x86_64::registers::segmentation::Segment;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 use lazy_static::lazy_static;
+use crate::cpu::{ self, MAX_CPUS };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+const STACK_SIZE: usize = 4096 * 5;
+
+// RSP0 はスレッド切り替えのたびに書き換えるため、TSS は可変な静的変数として持つ
+// CPU ごとに別のカーネルスタックを使うので、TSS も CPU ごとに持つ
+static mut TSS: [TaskStateSegment; MAX_CPUS] = [const { TaskStateSegment::new() }; MAX_CPUS];
+
+/// CPU ごとのダブルフォルト用スタック
+static mut DOUBLE_FAULT_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
 
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        
-        tss.privilege_stack_table[0] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+/// CPU ごとの、最初のスレッドに切り替えるまでの Ring 0 用スタック
+static mut PRIVILEGE_STACKS: [[u8; STACK_SIZE]; MAX_CPUS] = [[0; STACK_SIZE]; MAX_CPUS];
+
+/// cpu_id の TSS の各スタックを設定する
+fn init_tss(cpu_id: usize) {
+    let double_fault_stack = {
+        let stack_start = VirtAddr::from_ptr(unsafe { &raw const DOUBLE_FAULT_STACKS[cpu_id] });
+        stack_start + STACK_SIZE
+    };
 
-            let stack_start = VirtAddr::from_ptr(&raw const STACK);
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        
-        tss
+    let privilege_stack = {
+        let stack_start = VirtAddr::from_ptr(unsafe { &raw const PRIVILEGE_STACKS[cpu_id] });
+        stack_start + STACK_SIZE
     };
+
+    unsafe {
+        TSS[cpu_id].interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack;
+        TSS[cpu_id].privilege_stack_table[0] = privilege_stack;
+    }
+}
+
+/// 実行中の CPU で、Ring 3 から Ring 0 へ遷移するときに使うカーネルスタックを設定する
+/// 割り込みは TSS.RSP0、syscall は PerCpu の値を使う
+pub fn set_kernel_stack(stack_top: VirtAddr) {
+    unsafe {
+        TSS[cpu::id()].privilege_stack_table[0] = stack_top;
+    }
+    cpu::set_kernel_rsp(stack_top.as_u64());
 }
 
 pub struct Selectors {
-    kernel_code_selector: SegmentSelector,
-    kernel_data_selector: SegmentSelector,
+    pub kernel_code_selector: SegmentSelector,
+    pub kernel_data_selector: SegmentSelector,
     pub user_code_selector: SegmentSelector,
     pub user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
 lazy_static! {
-    pub static ref GDT: (GlobalDescriptorTable, Selectors) = {
+    /// CPU ごとの GDT
+    /// TSS のディスクリプタだけが異なり、セレクタの並びはすべての CPU で同じ
+    static ref GDTS: [(GlobalDescriptorTable, Selectors); MAX_CPUS] = core::array::from_fn(|cpu_id| {
         let mut gdt = GlobalDescriptorTable::new();
         let kernel_code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
         let kernel_data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
-        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        // SYSRET は STAR の値から SS = +8, CS = +16 を作るので、ユーザデータをユーザコードの前に置く
         let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = unsafe { &raw const TSS[cpu_id] };
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe { &*tss }));
         (gdt, Selectors { kernel_code_selector, kernel_data_selector, user_code_selector, user_data_selector, tss_selector })
-    };
+    });
+}
+
+/// セグメントセレクタ
+pub fn selectors() -> &'static Selectors {
+    &GDTS[0].1
 }
 
-pub fn init() {
-    use x86_64::instructions::segmentation::CS;
+/// cpu_id の CPU に GDT と TSS をロードする
+pub fn init(cpu_id: usize) {
+    use x86_64::instructions::segmentation::{ CS, SS };
     use x86_64::instructions::tables::load_tss;
 
-    GDT.0.load();                       // GlobalDescriptorTable
+    init_tss(cpu_id);
+    let (gdt, selectors) = &GDTS[cpu_id];
+    gdt.load();                         // GlobalDescriptorTable
     unsafe {
-        CS::set_reg(GDT.1.kernel_code_selector);    // Code Selector
-        load_tss(GDT.1.tss_selector);   // Task State Segment Selector
+        CS::set_reg(selectors.kernel_code_selector);    // Code Selector
+        SS::set_reg(selectors.kernel_data_selector);    // Stack Selector
+        load_tss(selectors.tss_selector);   // Task State Segment Selector
     }
 }
@@ -0,0 +1,273 @@
+use x86_64::PrivilegeLevel;
+use x86_64::registers::control::{ Cr0, Cr2, Cr3, Cr4 };
+use x86_64::structures::idt::{ InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode };
+use crate::{ backtrace, cpu, gdt, println };
+use crate::thread::THREAD_TABLE;
+use crate::thread::uprocess::{ self, signal };
+
+/// CPU 例外
+struct Exception {
+    vector: u8,
+    mnemonic: &'static str,
+    name: &'static str,
+    /// ユーザモードで発生したときにプロセスに送るシグナル
+    /// None ならユーザモードで発生してもカーネルの異常として扱う
+    signal: Option<i32>,
+    /// カーネルモードで発生しても実行を再開できるか
+    resumable: bool,
+}
+
+impl Exception {
+    const fn new(vector: u8, mnemonic: &'static str, name: &'static str, signal: Option<i32>) -> Self {
+        Exception { vector, mnemonic, name, signal, resumable: false }
+    }
+}
+
+const DIVIDE_ERROR: Exception = Exception::new(0, "#DE", "DIVIDE ERROR", Some(signal::SIGFPE));
+const DEBUG: Exception = Exception { resumable: true, ..Exception::new(1, "#DB", "DEBUG", Some(signal::SIGTRAP)) };
+const NON_MASKABLE_INTERRUPT: Exception = Exception::new(2, "NMI", "NON-MASKABLE INTERRUPT", None);
+const BREAKPOINT: Exception = Exception { resumable: true, ..Exception::new(3, "#BP", "BREAKPOINT", Some(signal::SIGTRAP)) };
+const OVERFLOW: Exception = Exception::new(4, "#OF", "OVERFLOW", Some(signal::SIGSEGV));
+const BOUND_RANGE_EXCEEDED: Exception = Exception::new(5, "#BR", "BOUND RANGE EXCEEDED", Some(signal::SIGSEGV));
+const INVALID_OPCODE: Exception = Exception::new(6, "#UD", "INVALID OPCODE", Some(signal::SIGILL));
+const DEVICE_NOT_AVAILABLE: Exception = Exception::new(7, "#NM", "DEVICE NOT AVAILABLE", Some(signal::SIGFPE));
+const DOUBLE_FAULT: Exception = Exception::new(8, "#DF", "DOUBLE FAULT", None);
+const INVALID_TSS: Exception = Exception::new(10, "#TS", "INVALID TSS", None);
+const SEGMENT_NOT_PRESENT: Exception = Exception::new(11, "#NP", "SEGMENT NOT PRESENT", Some(signal::SIGBUS));
+const STACK_SEGMENT_FAULT: Exception = Exception::new(12, "#SS", "STACK SEGMENT FAULT", Some(signal::SIGBUS));
+const GENERAL_PROTECTION_FAULT: Exception = Exception::new(13, "#GP", "GENERAL PROTECTION FAULT", Some(signal::SIGSEGV));
+const PAGE_FAULT: Exception = Exception::new(14, "#PF", "PAGE FAULT", Some(signal::SIGSEGV));
+const X87_FLOATING_POINT: Exception = Exception::new(16, "#MF", "x87 FLOATING POINT", Some(signal::SIGFPE));
+const ALIGNMENT_CHECK: Exception = Exception::new(17, "#AC", "ALIGNMENT CHECK", Some(signal::SIGBUS));
+const MACHINE_CHECK: Exception = Exception::new(18, "#MC", "MACHINE CHECK", None);
+const SIMD_FLOATING_POINT: Exception = Exception::new(19, "#XM", "SIMD FLOATING POINT", Some(signal::SIGFPE));
+const VIRTUALIZATION: Exception = Exception::new(20, "#VE", "VIRTUALIZATION", None);
+const CONTROL_PROTECTION: Exception = Exception::new(21, "#CP", "CONTROL PROTECTION", Some(signal::SIGSEGV));
+const HV_INJECTION: Exception = Exception::new(28, "#HV", "HYPERVISOR INJECTION", None);
+const VMM_COMMUNICATION: Exception = Exception::new(29, "#VC", "VMM COMMUNICATION", None);
+const SECURITY: Exception = Exception::new(30, "#SX", "SECURITY", None);
+
+/// 例外のエラーコード
+enum ErrorCode {
+    None,
+    /// セグメントセレクタを指すエラーコード (#TS, #NP, #SS, #GP)
+    Selector(u64),
+    PageFault(PageFaultErrorCode),
+    Raw(u64),
+}
+
+/// IDT に例外ハンドラを登録する
+pub fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    idt.divide_error.set_handler_fn(divide_error_handler);
+    idt.debug.set_handler_fn(debug_handler);
+    idt.non_maskable_interrupt.set_handler_fn(non_maskable_interrupt_handler);
+    // ユーザモードから int3 を使えるようにする
+    idt.breakpoint.set_handler_fn(breakpoint_handler).set_privilege_level(PrivilegeLevel::Ring3);
+    idt.overflow.set_handler_fn(overflow_handler);
+    idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.device_not_available.set_handler_fn(device_not_available_handler);
+    unsafe {
+        idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+    }
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault.set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
+    idt.alignment_check.set_handler_fn(alignment_check_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
+    idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
+    idt.virtualization.set_handler_fn(virtualization_handler);
+    idt.cp_protection_exception.set_handler_fn(control_protection_handler);
+    idt.hv_injection_exception.set_handler_fn(hv_injection_handler);
+    idt.vmm_communication_exception.set_handler_fn(vmm_communication_handler);
+    idt.security_exception.set_handler_fn(security_handler);
+}
+
+extern "x86-interrupt" fn divide_error_handler(stack_frame: InterruptStackFrame) {
+    handle(&DIVIDE_ERROR, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
+    handle(&DEBUG, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn non_maskable_interrupt_handler(stack_frame: InterruptStackFrame) {
+    handle(&NON_MASKABLE_INTERRUPT, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    handle(&BREAKPOINT, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+    handle(&OVERFLOW, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+    handle(&BOUND_RANGE_EXCEEDED, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    handle(&INVALID_OPCODE, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn device_not_available_handler(stack_frame: InterruptStackFrame) {
+    handle(&DEVICE_NOT_AVAILABLE, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    handle(&DOUBLE_FAULT, &stack_frame, ErrorCode::Raw(error_code));
+    unreachable!();
+}
+
+extern "x86-interrupt" fn invalid_tss_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&INVALID_TSS, &stack_frame, ErrorCode::Selector(error_code));
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&SEGMENT_NOT_PRESENT, &stack_frame, ErrorCode::Selector(error_code));
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&STACK_SEGMENT_FAULT, &stack_frame, ErrorCode::Selector(error_code));
+}
+
+extern "x86-interrupt" fn general_protection_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&GENERAL_PROTECTION_FAULT, &stack_frame, ErrorCode::Selector(error_code));
+}
+
+extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame, error_code: PageFaultErrorCode) {
+    handle(&PAGE_FAULT, &stack_frame, ErrorCode::PageFault(error_code));
+}
+
+extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
+    handle(&X87_FLOATING_POINT, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn alignment_check_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&ALIGNMENT_CHECK, &stack_frame, ErrorCode::Raw(error_code));
+}
+
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    handle(&MACHINE_CHECK, &stack_frame, ErrorCode::None);
+    unreachable!();
+}
+
+extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
+    handle(&SIMD_FLOATING_POINT, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn virtualization_handler(stack_frame: InterruptStackFrame) {
+    handle(&VIRTUALIZATION, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn control_protection_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&CONTROL_PROTECTION, &stack_frame, ErrorCode::Raw(error_code));
+}
+
+extern "x86-interrupt" fn hv_injection_handler(stack_frame: InterruptStackFrame) {
+    handle(&HV_INJECTION, &stack_frame, ErrorCode::None);
+}
+
+extern "x86-interrupt" fn vmm_communication_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&VMM_COMMUNICATION, &stack_frame, ErrorCode::Raw(error_code));
+}
+
+extern "x86-interrupt" fn security_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    handle(&SECURITY, &stack_frame, ErrorCode::Raw(error_code));
+}
+
+/// 例外の共通処理
+/// ユーザモードで発生した例外はそのプロセスだけをシグナルで終了させ、カーネルモードで発生した例外は panic する
+/// 例外ハンドラから直接呼ばれる前提で、呼び出し元のフレームから例外発生時の rbp を読む
+#[inline(never)]
+fn handle(exception: &Exception, stack_frame: &InterruptStackFrame, error_code: ErrorCode) {
+    // ユーザモードから入ったら GS ベースを切り替え、戻るときに元に戻す
+    let _gs = cpu::KernelGs::enter(stack_frame);
+
+    // ユーザモードからの COW ページへの書き込みなら、ページをコピーして再開する
+    let cow_fault = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE | PageFaultErrorCode::USER_MODE;
+    if let ErrorCode::PageFault(code) = error_code && code.contains(cow_fault) && uprocess::handle_cow_fault(Cr2::read()) {
+        return;
+    }
+
+    // [handle の rbp] に例外ハンドラの rbp、[例外ハンドラの rbp] に例外発生時の rbp が保存されている
+    let interrupted_rbp = unsafe {
+        let handler_rbp = *(backtrace::frame_pointer() as *const u64);
+        *(handler_rbp as *const u64)
+    };
+    dump(exception, stack_frame, &error_code, interrupted_rbp);
+
+    // CS の下位2ビットが CPL（現在の特権レベル）
+    let user_mode = stack_frame.code_segment & 0b11 == 3;
+    if user_mode && let Some(signal) = exception.signal {
+        kill_current(signal, stack_frame, &error_code);
+    }
+    if !user_mode && exception.resumable {
+        return;
+    }
+
+    panic!("EXCEPTION: {} {}", exception.mnemonic, exception.name);
+}
+
+/// 例外を起こしたプロセスをシグナルで終了させる
+fn kill_current(signal: i32, stack_frame: &InterruptStackFrame, error_code: &ErrorCode) -> ! {
+    let pid = uprocess::current_pid();
+    match error_code {
+        ErrorCode::PageFault(_) => println!("pid {:?}: killed by {} (signal {}) addr={:#x} rip={:#x}",
+            pid, signal::name(signal), signal, Cr2::read().as_u64(), stack_frame.instruction_pointer.as_u64()),
+        _ => println!("pid {:?}: killed by {} (signal {}) rip={:#x}",
+            pid, signal::name(signal), signal, stack_frame.instruction_pointer.as_u64()),
+    }
+    uprocess::exit::kill(signal);
+}
+
+/// 例外の情報を表示する
+fn dump(exception: &Exception, stack_frame: &InterruptStackFrame, error_code: &ErrorCode, interrupted_rbp: u64) {
+    println!("EXCEPTION: {} {} (vector {})", exception.mnemonic, exception.name, exception.vector);
+
+    match error_code {
+        ErrorCode::None => {}
+        ErrorCode::Selector(code) => {
+            // bit 0: 外部イベント, bit 1-2: 参照したテーブル, bit 3-15: セレクタインデックス
+            let table = match (code >> 1) & 0b11 {
+                0b00 => "GDT",
+                0b10 => "LDT",
+                _ => "IDT",
+            };
+            println!("Error Code: {:#x} (external={}, table={}, index={:#x})",
+                code, code & 1 != 0, table, (code >> 3) & 0x1fff);
+        }
+        ErrorCode::PageFault(code) => println!("Error Code: {:?}", code),
+        ErrorCode::Raw(code) => println!("Error Code: {:#x}", code),
+    }
+
+    let (cr3_frame, cr3_flags) = Cr3::read();
+    println!("CR0: {:?}", Cr0::read());
+    println!("CR2: {:#x}", Cr2::read().as_u64());
+    println!("CR3: {:#x} {:?}", cr3_frame.start_address().as_u64(), cr3_flags);
+    println!("CR4: {:?}", Cr4::read());
+
+    // 例外がロックを握ったまま発生していることがあるので、try_lock で読む
+    if cpu::is_initialized() {
+        let tid = cpu::current_tid();
+        let pid = tid.and_then(|tid| THREAD_TABLE.try_lock().and_then(|table| table[tid].pid));
+        println!("CPU {}: tid={:?} pid={:?}", cpu::id(), tid, pid);
+    }
+
+    println!("{:#?}", stack_frame);
+
+    // ユーザモードの rbp はユーザスタックを指すので、カーネルモードのときだけたどる
+    if stack_frame.code_segment & 0b11 != 3 {
+        backtrace::print_from(stack_frame.instruction_pointer.as_u64(), interrupted_rbp);
+    }
+}
+
+#[test_case]
+fn test_vector_numbers() {
+    assert_eq!(GENERAL_PROTECTION_FAULT.vector, 13);
+    assert_eq!(PAGE_FAULT.vector, 14);
+}
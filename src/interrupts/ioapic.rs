@@ -0,0 +1,89 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::{ PhysAddr, VirtAddr };
+use crate::memory;
+
+/// レジスタ選択・データウィンドウのオフセット
+const IOREGSEL: u64 = 0x00;
+const IOWIN: u64 = 0x10;
+
+/// レジスタ
+const IOAPICVER: u32 = 0x01;
+const IOREDTBL: u32 = 0x10;
+
+/// リダイレクションエントリのフラグ
+const REDIRECT_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECT_LEVEL: u32 = 1 << 15;
+const REDIRECT_MASKED: u32 = 1 << 16;
+
+/// I/O APIC
+struct IoApic {
+    base: VirtAddr,
+    /// このI/O APIC が受け持つ最初の GSI
+    gsi_base: u32,
+    /// リダイレクションエントリの数
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            core::ptr::read_volatile((self.base + IOWIN).as_ptr::<u32>())
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.base + IOREGSEL).as_mut_ptr::<u32>(), reg);
+            core::ptr::write_volatile((self.base + IOWIN).as_mut_ptr::<u32>(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.entries).contains(&gsi)
+    }
+
+    fn write_entry(&self, gsi: u32, low: u32, high: u32) {
+        let index = gsi - self.gsi_base;
+        // 上位を先に書き、マスクを外すのは最後にする
+        self.write(IOREDTBL + index * 2, REDIRECT_MASKED);
+        self.write(IOREDTBL + index * 2 + 1, high);
+        self.write(IOREDTBL + index * 2, low);
+    }
+}
+
+static IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+
+/// I/O APIC を登録し、全エントリをマスクする
+pub fn add(phys: PhysAddr, gsi_base: u32) -> Result<(), &'static str> {
+    let base = memory::map_mmio(phys, 0x20)?;
+    let mut ioapic = IoApic { base, gsi_base, entries: 0 };
+    // bit 16-23 が最大のエントリ番号
+    ioapic.entries = ((ioapic.read(IOAPICVER) >> 16) & 0xff) + 1;
+    for gsi in gsi_base..gsi_base + ioapic.entries {
+        ioapic.write_entry(gsi, REDIRECT_MASKED, 0);
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| IOAPICS.lock().push(ioapic));
+    Ok(())
+}
+
+/// gsi の割り込みを APIC ID dest の CPU の vector に送る
+pub fn route(gsi: u32, vector: u8, dest: u8, active_low: bool, level_triggered: bool) -> Result<(), &'static str> {
+    let mut low = vector as u32;
+    if active_low {
+        low |= REDIRECT_ACTIVE_LOW;
+    }
+    if level_triggered {
+        low |= REDIRECT_LEVEL;
+    }
+    let high = (dest as u32) << 24;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let ioapics = IOAPICS.lock();
+        let ioapic = ioapics.iter().find(|ioapic| ioapic.handles(gsi)).ok_or("no I/O APIC for GSI")?;
+        ioapic.write_entry(gsi, low, high);
+        Ok(())
+    })
+}
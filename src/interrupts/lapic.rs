@@ -0,0 +1,145 @@
+use core::sync::atomic::{ AtomicU64, Ordering };
+use x86_64::{ PhysAddr, VirtAddr };
+use x86_64::registers::model_specific::Msr;
+use crate::{ memory, time::pit };
+
+/// レジスタのオフセット
+const ID: u64 = 0x20;
+const TPR: u64 = 0x80;
+const EOI: u64 = 0xB0;
+const SVR: u64 = 0xF0;
+const ICR_LOW: u64 = 0x300;
+const ICR_HIGH: u64 = 0x310;
+const LVT_TIMER: u64 = 0x320;
+const LVT_LINT0: u64 = 0x350;
+const LVT_ERROR: u64 = 0x370;
+const TIMER_INITIAL_COUNT: u64 = 0x380;
+const TIMER_CURRENT_COUNT: u64 = 0x390;
+const TIMER_DIVIDE: u64 = 0x3E0;
+
+/// SVR: APIC を有効にする
+const SVR_ENABLE: u32 = 1 << 8;
+/// LVT: 割り込みをマスクする
+const LVT_MASKED: u32 = 1 << 16;
+/// LVT_TIMER: 周期モード
+const TIMER_PERIODIC: u32 = 1 << 17;
+/// TIMER_DIVIDE: バスクロックを 16 分周する
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+/// ICR: INIT
+const ICR_INIT: u32 = 0b101 << 8;
+/// ICR: Startup IPI
+const ICR_STARTUP: u32 = 0b110 << 8;
+/// ICR: 送信中
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+/// ICR: アサート
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+/// ICR: レベルトリガ
+const ICR_TRIGGER_LEVEL: u32 = 1 << 15;
+
+/// IA32_APIC_BASE MSR
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/// タイマの計測で PIT を待つクロック数 (約 10ms)
+const CALIBRATION_PIT_COUNT: u16 = 11932;
+
+/// レジスタをマップした仮想アドレス
+/// 0 なら未初期化
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Local APIC のレジスタをマップして、この CPU の Local APIC を有効にする
+/// レジスタの物理アドレスはすべての CPU で同じなので、マップは一度だけ行う
+pub fn init(phys: PhysAddr, spurious_vector: u8) -> Result<(), &'static str> {
+    let base = memory::map_mmio(phys, 4096)?;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    init_cpu(spurious_vector);
+    Ok(())
+}
+
+/// この CPU の Local APIC を有効にする
+/// 8259 からの割り込み (LINT0 の ExtINT) とエラー割り込みはマスクする
+pub fn init_cpu(spurious_vector: u8) {
+    unsafe {
+        let mut msr = Msr::new(IA32_APIC_BASE);
+        let value = msr.read();
+        msr.write(value | APIC_BASE_ENABLE);
+    }
+
+    write(TPR, 0);
+    write(LVT_LINT0, LVT_MASKED);
+    write(LVT_ERROR, LVT_MASKED);
+    write(SVR, SVR_ENABLE | spurious_vector as u32);
+}
+
+/// Local APIC が使えるか
+pub fn is_enabled() -> bool {
+    BASE.load(Ordering::Relaxed) != 0
+}
+
+/// この CPU の APIC ID
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+/// 割り込み処理の終了を通知する
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+/// タイマの速さを PIT を基準に測り、hz に近い周期にするための (1周期のカウント数, カウントの周波数) を返す
+/// バスクロックはすべての CPU で同じなので、BSP で一度だけ測る
+pub fn calibrate_timer(hz: u64) -> (u64, u64) {
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, LVT_MASKED);
+
+    // PIT で約 10ms 待つ間にどれだけ減るかを測る
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    pit::busy_wait(CALIBRATION_PIT_COUNT);
+    let elapsed = (u32::MAX - read(TIMER_CURRENT_COUNT)) as u64;
+    write(TIMER_INITIAL_COUNT, 0);
+
+    let timer_hz = elapsed * pit::FREQUENCY_HZ / CALIBRATION_PIT_COUNT as u64;
+    let count = (timer_hz / hz.max(1)).clamp(1, u32::MAX as u64);
+    (count, timer_hz)
+}
+
+/// この CPU のタイマを count ごとに vector に割り込ませる
+pub fn start_timer(vector: u8, count: u64) {
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, TIMER_PERIODIC | vector as u32);
+    write(TIMER_INITIAL_COUNT, count as u32);
+}
+
+/// APIC ID dest の CPU に INIT IPI を送る
+pub fn send_init(dest: u8) {
+    send_ipi(dest, ICR_INIT | ICR_LEVEL_ASSERT | ICR_TRIGGER_LEVEL);
+}
+
+/// APIC ID dest の CPU に Startup IPI を送る
+/// CPU は物理アドレス page * 4096 からリアルモードで実行を始める
+pub fn send_startup(dest: u8, page: u8) {
+    send_ipi(dest, ICR_STARTUP | ICR_LEVEL_ASSERT | page as u32);
+}
+
+fn send_ipi(dest: u8, command: u32) {
+    write(ICR_HIGH, (dest as u32) << 24);
+    write(ICR_LOW, command);
+    while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+fn register(offset: u64) -> *mut u32 {
+    let base = BASE.load(Ordering::Relaxed);
+    assert!(base != 0, "local APIC not initialized");
+    VirtAddr::new(base + offset).as_mut_ptr()
+}
+
+fn read(offset: u64) -> u32 {
+    unsafe { core::ptr::read_volatile(register(offset)) }
+}
+
+fn write(offset: u64, value: u32) {
+    unsafe { core::ptr::write_volatile(register(offset), value) }
+}
@@ -0,0 +1,241 @@
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use lazy_static::lazy_static;
+use pic8259::ChainedPics;
+use spin;
+use core::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use crate::println;
+use crate::{ cpu, scheduler, thread, time };
+use crate::acpi::madt::{ self, Madt, MadtEntry };
+
+mod exception;
+pub mod lapic;
+pub mod ioapic;
+
+// まだヒープが存在しないため、IDT は静的変数として定義する
+lazy_static! {
+    static ref IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        exception::set_handlers(&mut idt);
+        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
+        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial.as_usize()].set_handler_fn(serial_interrupt_handler);
+        idt[InterruptIndex::PicSpurious1.as_usize()].set_handler_fn(pic_spurious_interrupt_handler);
+        idt[InterruptIndex::PicSpurious2.as_usize()].set_handler_fn(pic_spurious_interrupt_handler);
+        idt[InterruptIndex::ApicSpurious.as_usize()].set_handler_fn(apic_spurious_interrupt_handler);
+
+        idt
+    };
+}
+pub fn init_idt() {
+    IDT.load();
+}
+
+/// 割り込みコントローラに Local APIC / I/O APIC を使っているか
+/// false なら 8259 PIC を使っている
+static APIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Local APIC タイマの1周期のカウント数
+/// AP も BSP で測った値を使う
+static APIC_TIMER_COUNT: AtomicU64 = AtomicU64::new(0);
+
+pub fn apic_enabled() -> bool {
+    APIC_ENABLED.load(Ordering::Relaxed)
+}
+
+/// 8259 PIC を初期化する
+/// APIC に切り替えるまでの割り込みはこちらで受ける
+pub fn init_pic() {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        // タイマ・キーボード・IRQ4 (シリアル) 以外はマスク
+        let master_mask = !((1 << 0) | (1 << 1) | (1 << 2) | (1 << 4));
+        pics.write_masks(master_mask, 0xff);
+    }
+}
+
+/// MADT をもとに Local APIC と I/O APIC を初期化し、8259 PIC から切り替える
+/// 失敗した場合は 8259 PIC のまま使い続ける
+pub fn init_apic() -> Result<(), &'static str> {
+    let madt = Madt::get().ok_or("MADT not found")?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        lapic::init(x86_64::PhysAddr::new(madt.local_apic_address()), InterruptIndex::ApicSpurious.as_u8())?;
+
+        for entry in madt.entries() {
+            if let MadtEntry::IoApic { address, gsi_base, .. } = entry {
+                ioapic::add(x86_64::PhysAddr::new(address as u64), gsi_base)?;
+            }
+        }
+
+        // ISA の IRQ を BSP に送る
+        let bsp = lapic::id();
+        route_isa_irq(&madt, 1, InterruptIndex::Keyboard.as_u8(), bsp)?;
+        route_isa_irq(&madt, 4, InterruptIndex::Serial.as_u8(), bsp)?;
+
+        // 8259 PIC は全てマスクして使わない
+        unsafe {
+            PICS.lock().disable();
+        }
+
+        // PIT に代わって Local APIC タイマで時刻を進める
+        let (count, timer_hz) = lapic::calibrate_timer(time::TICK_HZ);
+        lapic::start_timer(InterruptIndex::Timer.as_u8(), count);
+        time::set_tick_period(count, timer_hz);
+        APIC_TIMER_COUNT.store(count, Ordering::Relaxed);
+
+        APIC_ENABLED.store(true, Ordering::Relaxed);
+        Ok(())
+    })
+}
+
+/// AP の Local APIC を有効にし、BSP と同じ周期でタイマを動かす
+/// init_apic の後に、AP 自身が割り込みを禁止した状態で呼ぶ
+pub fn init_ap() {
+    lapic::init_cpu(InterruptIndex::ApicSpurious.as_u8());
+    lapic::start_timer(InterruptIndex::Timer.as_u8(), APIC_TIMER_COUNT.load(Ordering::Relaxed));
+}
+
+/// ISA の irq を vector に送る
+/// Interrupt Source Override があればその GSI・極性・トリガに従い、なければ ISA の既定 (High, Edge) とする
+fn route_isa_irq(madt: &Madt, irq: u8, vector: u8, dest: u8) -> Result<(), &'static str> {
+    let (gsi, flags) = madt.entries()
+        .find_map(|entry| match entry {
+            MadtEntry::InterruptSourceOverride { bus: 0, source, gsi, flags } if source == irq => Some((gsi, flags)),
+            _ => None,
+        })
+        .unwrap_or((irq as u32, 0));
+
+    let active_low = flags & madt::POLARITY_MASK == madt::POLARITY_ACTIVE_LOW;
+    let level_triggered = flags & madt::TRIGGER_MASK == madt::TRIGGER_LEVEL;
+    ioapic::route(gsi, vector, dest, active_low, level_triggered)
+}
+
+/// 割り込み処理の終了を割り込みコントローラに通知する
+fn end_of_interrupt(index: InterruptIndex) {
+    if apic_enabled() {
+        lapic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
+/// タイマ割り込みハンドラ
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    // ユーザモードから入ったら GS ベースを切り替え、戻るときに元に戻す
+    let _gs = cpu::KernelGs::enter(&stack_frame);
+
+    end_of_interrupt(InterruptIndex::Timer);
+
+    // 時刻を進め、期限を迎えたスレッドを起こす
+    // どの CPU もタイマ割り込みを受けるので、時刻を進めるのは BSP だけにする
+    if cpu::id() == 0 {
+        time::tick();
+    }
+    thread::account_tick();
+
+    // CS の下位2ビットが CPL（現在の特権レベル）
+    let cpl = stack_frame.code_segment & 0b11;
+    if cpl == 3 {
+        println!("Ring 3 confirmed! rip={:#x}", stack_frame.instruction_pointer);
+    }
+
+    // プリエンプトするかはスケジューラが決める
+    // preempt_disable の間はプリエンプトしない
+    if scheduler::SCHEDULER_STARTED.load(Ordering::Relaxed) && scheduler::tick() && cpu::preempt_count() == 0 {
+        scheduler::yield_from_context();
+    }
+}
+
+/// キーボード割り込みハンドラ
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    // ユーザモードから入ったら GS ベースを切り替え、戻るときに元に戻す
+    let _gs = cpu::KernelGs::enter(&stack_frame);
+
+    use x86_64::instructions::port::Port;
+    
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe {
+        port.read()
+    };
+    crate::task::keyboard::add_scancode(scancode);
+
+    end_of_interrupt(InterruptIndex::Keyboard);
+}
+
+/// シリアル割り込みハンドラ
+extern "x86-interrupt" fn serial_interrupt_handler(stack_frame: InterruptStackFrame) {
+    // ユーザモードから入ったら GS ベースを切り替え、戻るときに元に戻す
+    let _gs = cpu::KernelGs::enter(&stack_frame);
+
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x3F8);
+    let byte: u8 = unsafe {
+        port.read()
+    };
+
+    // キーボードタスクに渡す
+    crate::task::serial_input::add_byte(byte);
+
+    end_of_interrupt(InterruptIndex::Serial);
+}
+
+/// 8259 PIC の spurious 割り込みハンドラ
+/// 実際の割り込みではないので EOI は送らない
+extern "x86-interrupt" fn pic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+/// Local APIC の spurious 割り込みハンドラ
+/// EOI は送らない
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+// 割り込み
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+// PIC の offset は 32 ~ 47 にマップ
+pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(
+    unsafe { 
+        ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) 
+    }
+);
+
+#[derive(Debug, Clone, Copy)]
+#[repr(u8)]
+pub enum InterruptIndex {
+    Timer = PIC_1_OFFSET,
+    Keyboard,
+    Serial = PIC_1_OFFSET + 4,  // COM1, IRQ4
+    PicSpurious1 = PIC_1_OFFSET + 7,
+    PicSpurious2 = PIC_2_OFFSET + 7,
+    ApicSpurious = 0xFF,
+}
+impl InterruptIndex {
+    fn as_u8(self) -> u8 {
+        self as u8
+    }
+    fn as_usize(self) -> usize {
+        usize::from(self.as_u8())
+    }
+}
+
+#[test_case]
+fn test_breakpoint_exception() {
+    // invoke a breakpoint exception
+    x86_64::instructions::interrupts::int3();
+}
+
+#[test_case]
+fn check_interrupt_indexes() {
+    assert!(InterruptIndex::Timer.as_usize() == 32);
+    assert!(InterruptIndex::Keyboard.as_usize() == 33);
+}
+
+#[test_case]
+fn check_spurious_indexes() {
+    // PIC の spurious は IRQ7 / IRQ15、APIC の spurious は下位4ビットが 1 でなければならない
+    assert_eq!(InterruptIndex::PicSpurious1.as_usize(), 39);
+    assert_eq!(InterruptIndex::PicSpurious2.as_usize(), 47);
+    assert_eq!(InterruptIndex::ApicSpurious.as_u8() & 0x0f, 0x0f);
+}
@@ -14,8 +14,15 @@ pub mod allocator;
 pub mod task;
 pub mod thread;
 pub mod cpu;
+pub mod fpu;
 pub mod console;
 pub mod scheduler;
+pub mod syscall;
+pub mod backtrace;
+pub mod symbols;
+pub mod time;
+pub mod acpi;
+pub mod smp;
 
 mod libbackend;
 pub use libbackend::exit::*;
@@ -1,5 +1,5 @@
 use super::exit::*;
-use super::super::{ gdt, interrupts, serial_println };
+use super::super::{ cpu, fpu, gdt, interrupts, syscall, time, serial_println };
 use crate::hlt_loop;
 use core::panic::PanicInfo;
 
@@ -8,18 +8,13 @@ extern crate alloc;
 /// init
 /// IDT の初期化
 pub fn init() {
-    gdt::init();
+    cpu::init(0);
+    fpu::init();
+    gdt::init(0);
     interrupts::init_idt();
-    unsafe {
-        interrupts::PICS.lock().initialize();
-    }
-    // IRQ4 のマスクを解除
-    unsafe {
-        use x86_64::instructions::port::Port;
-        let mut port = Port::<u8>::new(0x21);
-        let mask = port.read();
-        port.write(mask & !(1 << 4));
-    }
+    syscall::init();
+    interrupts::init_pic();
+    time::init();
     x86_64::instructions::interrupts::enable();
 }
 
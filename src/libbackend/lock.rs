@@ -1,15 +1,89 @@
 // トレイト実装を許してもらうための spin::Mutex をラップする型
+// ヒープのロックに使うので、ロック中にプリエンプトされないよう割り込みを禁止する
+// (割り込みを禁止した状態でヒープを使うコードが、同じ CPU で回り続けないように)
 pub struct Locked<A> {
-    inner: spin::Mutex<A>,
+    inner: IrqMutex<A>,
 }
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: spin::Mutex::new(inner),
+            inner: IrqMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqMutexGuard<'_, A> {
         self.inner.lock()
     }
 }
+
+/// ロックしている間は割り込みを禁止する spin::Mutex
+/// 割り込みハンドラからもロックするデータに使う
+pub struct IrqMutex<T> {
+    inner: spin::Mutex<T>,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(inner: T) -> Self {
+        IrqMutex {
+            inner: spin::Mutex::new(inner),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<'_, T> {
+        let were_enabled = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+        IrqMutexGuard {
+            guard: core::mem::ManuallyDrop::new(self.inner.lock()),
+            were_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<'_, T>> {
+        let were_enabled = x86_64::instructions::interrupts::are_enabled();
+        x86_64::instructions::interrupts::disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: core::mem::ManuallyDrop::new(guard),
+                were_enabled,
+            }),
+            None => {
+                if were_enabled {
+                    x86_64::instructions::interrupts::enable();
+                }
+                None
+            }
+        }
+    }
+}
+
+/// IrqMutex のガード
+/// ロックを外してから、ロック前の割り込みの状態に戻す
+pub struct IrqMutexGuard<'a, T> {
+    guard: core::mem::ManuallyDrop<spin::MutexGuard<'a, T>>,
+    were_enabled: bool,
+}
+
+impl<T> core::ops::Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> core::ops::DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe {
+            core::mem::ManuallyDrop::drop(&mut self.guard);
+        }
+        if self.were_enabled {
+            x86_64::instructions::interrupts::enable();
+        }
+    }
+}
@@ -11,9 +11,11 @@ use ferrios::task::keyboard;
 use bootloader::{ BootInfo, entry_point };
 use ferrios::task::serial_input;
 use core::panic::PanicInfo;
+use core::time::Duration;
 use alloc::{ boxed::Box, vec, vec::Vec, rc::Rc };
 
 use ferrios::{ println, print };
+use ferrios::time;
 use ferrios::memory;
 use ferrios::allocator;
 use ferrios::task::{ Task, executor::Executor };
@@ -34,7 +36,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     print!("Initializing..");
     ferrios::init();
     console::init();
-    scheduler::init(Box::new(scheduler::round_robin::RoundRobin));
     println!("done.");
     
     let console_mode = console::CONSOLE.lock().get();
@@ -79,6 +80,27 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     println!("Initializing heap memory..");
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
 
+    // 以降のフレーム確保は共有のアロケータから行う
+    memory::init_frame_allocator(frame_allocator);
+    // スケジューラはヒープに置くので、ヒープを初期化してから登録する
+    scheduler::init(Box::new(scheduler::priority::Priority::new()));
+    // 後からのカーネルのマッピングもプロセスと共有されるよう、カーネルの level3 テーブルを用意する
+    thread::uprocess::address_space::init_kernel_tables().expect("failed to allocate kernel page tables");
+
+    // ACPI のテーブルを探す
+    println!("Initializing ACPI..");
+    ferrios::acpi::init();
+    for table in ferrios::acpi::tables() {
+        println!("\t{} rev {} ({})", table.signature_str(), table.revision, table.oem_id_str());
+    }
+
+    // APIC の構成を MADT から読み、8259 PIC から切り替える
+    println!("Initializing APIC..");
+    match ferrios::interrupts::init_apic() {
+        Ok(()) => println!("\tlocal APIC id {}", ferrios::interrupts::lapic::id()),
+        Err(e) => println!("\tAPIC unavailable ({}), using 8259 PIC", e),
+    }
+
     // allocates
     let x = Box::new(41);
     println!("\theap_value at {:p}", x);
@@ -100,19 +122,103 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     
     // カーネルスレッド作成
     print!("Starting kernel threads..");
-    thread::kthread::create_kernel_thread(kernel_thread_0);
-    thread::kthread::create_kernel_thread(kernel_thread_1);
-    thread::kthread::create_kernel_thread(keyboard_and_serial_input_thread);
+    thread::kthread::create_kernel_thread("thread0", kernel_thread_0).expect("failed to create thread0");
+    thread::kthread::create_kernel_thread("thread1", kernel_thread_1).expect("failed to create thread1");
+    thread::kthread::create_kernel_thread("sleeper", sleeper_thread).expect("failed to create sleeper");
+    // 入力を処理するスレッドは、CPU を使い続けるスレッドより先に動かす
+    let input_tid = thread::kthread::create_kernel_thread("input", keyboard_and_serial_input_thread)
+        .expect("failed to create input thread");
+    scheduler::set_priority(input_tid, thread::KERNEL_PRIORITY).expect("failed to set input thread priority");
     println!("done.");
 
     // ユーザプロセス作成
-    const USER_CODE: &[u8] = &[
-        0x48, 0x31, 0xC0,           // xor rax, rax
-        0x48, 0xFF, 0xC0,           // inc rax
-        0xEB, 0xFB,                 // jmp -5
+    // USER_CODE_START にロードされる静的な ELF64 実行ファイル
+    // init: /bin/hello を fork & exec し、終了した子を wait で回収し続ける
+    const INIT_PROGRAM: &[u8] = &[
+        // ELF ヘッダ
+        0x7F, b'E', b'L', b'F', 0x02, 0x01, 0x01, 0x00,     // magic, ELFCLASS64, little endian, version, SysV ABI
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // padding
+        0x02, 0x00, 0x3E, 0x00, 0x01, 0x00, 0x00, 0x00,     // e_type = ET_EXEC, e_machine = x86_64, e_version
+        0x78, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,     // e_entry = 0x1000_0000_0078
+        0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // e_phoff = 64
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // e_shoff
+        0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00,     // e_flags, e_ehsize = 64, e_phentsize = 56
+        0x01, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,     // e_phnum = 1, e_shentsize, e_shnum, e_shstrndx
+        // プログラムヘッダ
+        0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,     // p_type = PT_LOAD, p_flags = R+X
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_offset = 0
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,     // p_vaddr = 0x1000_0000_0000
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,     // p_paddr
+        0xB8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_filesz = 184
+        0xB8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_memsz = 184
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_align = 0x1000
+        // コード (offset 0x78)
+        0xB8, 0x01, 0x00, 0x00, 0x00,               // mov eax, 1 (SYS_FORK)
+        0x0F, 0x05,                                 // syscall
+        0x48, 0x85, 0xC0,                           // test rax, rax
+        0x75, 0x1E,                                 // jnz parent
+        0xB8, 0x07, 0x00, 0x00, 0x00,               // mov eax, 7 (SYS_EXEC)
+        0x48, 0x8D, 0x3D, 0x1D, 0x00, 0x00, 0x00,   // lea rdi, [rip + 29] (path)
+        0x31, 0xF6,                                 // xor esi, esi (argv = NULL)
+        0x31, 0xD2,                                 // xor edx, edx (envp = NULL)
+        0x0F, 0x05,                                 // syscall
+        0xB8, 0x02, 0x00, 0x00, 0x00,               // mov eax, 2 (SYS_EXIT)
+        0xBF, 0x01, 0x00, 0x00, 0x00,               // mov edi, 1
+        0x0F, 0x05,                                 // syscall
+        // parent:
+        0xB8, 0x03, 0x00, 0x00, 0x00,               // mov eax, 3 (SYS_WAIT)
+        0x31, 0xFF,                                 // xor edi, edi (status = NULL)
+        0x0F, 0x05,                                 // syscall
+        0xEB, 0xF5,                                 // jmp parent
+        // path: "/bin/hello"
+        b'/', b'b', b'i', b'n', b'/', b'h', b'e', b'l', b'l', b'o', 0x00,
+    ];
+
+    // /bin/hello: メッセージを表示して CPU を譲り続ける
+    const USER_PROGRAM: &[u8] = &[
+        // ELF ヘッダ
+        0x7F, b'E', b'L', b'F', 0x02, 0x01, 0x01, 0x00,     // magic, ELFCLASS64, little endian, version, SysV ABI
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // padding
+        0x02, 0x00, 0x3E, 0x00, 0x01, 0x00, 0x00, 0x00,     // e_type = ET_EXEC, e_machine = x86_64, e_version
+        0x78, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,     // e_entry = 0x1000_0000_0078
+        0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // e_phoff = 64
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // e_shoff
+        0x00, 0x00, 0x00, 0x00, 0x40, 0x00, 0x38, 0x00,     // e_flags, e_ehsize = 64, e_phentsize = 56
+        0x01, 0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00,     // e_phnum = 1, e_shentsize, e_shnum, e_shstrndx
+        // プログラムヘッダ
+        0x01, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,     // p_type = PT_LOAD, p_flags = R+X
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_offset = 0
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,     // p_vaddr = 0x1000_0000_0000
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00,     // p_paddr
+        0xAC, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_filesz = 172
+        0xAC, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_memsz = 172
+        0x00, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,     // p_align = 0x1000
+        // コード (offset 0x78)
+        0xB8, 0x10, 0x00, 0x00, 0x00,               // mov eax, 16 (SYS_WRITE)
+        0xBF, 0x01, 0x00, 0x00, 0x00,               // mov edi, 1 (stdout)
+        0x48, 0x8D, 0x35, 0x10, 0x00, 0x00, 0x00,   // lea rsi, [rip + 16] (msg)
+        0xBA, 0x13, 0x00, 0x00, 0x00,               // mov edx, 19 (len)
+        0x0F, 0x05,                                 // syscall
+        0xB8, 0x16, 0x00, 0x00, 0x00,               // mov eax, 22 (SYS_YIELD)
+        0x0F, 0x05,                                 // syscall
+        0xEB, 0xDF,                                 // jmp -33
+        // msg: "Hello from ring 3!\n"
+        b'H', b'e', b'l', b'l', b'o', b' ', b'f', b'r', b'o', b'm', b' ',
+        b'r', b'i', b'n', b'g', b' ', b'3', b'!', b'\n',
     ];
 
-    thread::uprocess::create_user_process(USER_CODE, &mut mapper, &mut frame_allocator).expect("failed to create user process");
+    // exec から実行できるように登録しておく
+    thread::uprocess::programs::register("/bin/hello", USER_PROGRAM);
+
+    // 最初に作成したプロセスが init になる
+    thread::uprocess::create_user_process("init", INIT_PROGRAM).expect("failed to create init process");
+
+    // AP を起動し、それぞれのスケジューラを実行させる
+    print!("Starting application processors..");
+    match ferrios::smp::init() {
+        Ok(started) => println!("{} started.", started),
+        Err(e) => println!("skipped ({}).", e),
+    }
 
     println!("Starting the scheduler..");
     scheduler::scheduler();
@@ -144,6 +250,14 @@ fn kernel_thread_1() -> ! {
     }
 }
 
+// sleep_for で眠り、起きるたびに起動からの時間を表示するスレッド
+fn sleeper_thread() -> ! {
+    loop {
+        thread::sleep_for(Duration::from_millis(500));
+        println!("Sleeper woke at {} ms", time::uptime().as_millis());
+    }
+}
+
 // キーボード＆シリアル割り込み用スレッド
 fn keyboard_and_serial_input_thread() -> ! {
     let mut executor = Executor::new();
@@ -158,6 +272,7 @@ fn keyboard_and_serial_input_thread() -> ! {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    ferrios::backtrace::print();
     ferrios::hlt_loop();
 }
 
@@ -1,9 +1,42 @@
 use x86_64::{ VirtAddr, PhysAddr };
-use x86_64::structures::paging::{ PageTable, OffsetPageTable, Page, PhysFrame, Mapper, Size4KiB, FrameAllocator };
+use x86_64::structures::paging::{ PageTable, OffsetPageTable, Page, PhysFrame, Mapper, Size4KiB, FrameAllocator, FrameDeallocator };
+use x86_64::structures::paging::PageTableFlags;
 use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+use conquer_once::spin::OnceCell;
+use spin::Mutex;
+use alloc::collections::BTreeMap;
+
+/// 物理メモリ全体がマップされている仮想アドレスのオフセット
+static PHYSICAL_MEMORY_OFFSET: OnceCell<VirtAddr> = OnceCell::uninit();
+
+/// ブートローダが用意したカーネルの level4 テーブル
+/// プロセスのページテーブルはここからカーネル部分をコピーする
+static KERNEL_PML4: OnceCell<PhysFrame> = OnceCell::uninit();
+
+/// デバイスのレジスタ (MMIO) をマップする仮想アドレスの範囲
+const MMIO_START: u64 = 0x5555_0000_0000;
+const MMIO_END: u64 = MMIO_START + 0x1_0000_0000;
+
+/// 次に MMIO をマップする仮想アドレス
+static MMIO_NEXT: Mutex<u64> = Mutex::new(MMIO_START);
+
+/// リアルモードから使える 1 MiB 未満の領域
+/// AP の起動コードを置くフレームを 1 つだけ、ここからフレームアロケータに渡さずに取っておく
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
+/// カーネル全体で共有するフレームアロケータ
+static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
+
+/// 複数のページテーブルから参照されているフレームの参照数
+/// 登録されていないフレームの参照数は 1 とみなす
+static FRAME_REFCOUNT: Mutex<BTreeMap<PhysFrame, usize>> = Mutex::new(BTreeMap::new());
 
 /// 新しい OffsetPageTable を初期化する
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    use x86_64::registers::control::Cr3;
+
+    PHYSICAL_MEMORY_OFFSET.init_once(|| physical_memory_offset);
+    KERNEL_PML4.init_once(|| Cr3::read().0);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -13,6 +46,231 @@ pub unsafe fn translate_addr(addr: VirtAddr, physical_memory_offset: VirtAddr) -
     translate_addr_inner(addr, physical_memory_offset)
 }
 
+/// 物理メモリ全体がマップされている仮想アドレスのオフセット
+pub fn physical_memory_offset() -> VirtAddr {
+    *PHYSICAL_MEMORY_OFFSET.get().expect("memory not initialized")
+}
+
+/// 物理アドレスを、それがマップされている仮想アドレスに変換する
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    physical_memory_offset() + phys.as_u64()
+}
+
+/// カーネルの level4 テーブルのフレーム
+pub fn kernel_pml4() -> PhysFrame {
+    *KERNEL_PML4.get().expect("memory not initialized")
+}
+
+/// フレームアロケータをカーネル全体で使えるようにする
+/// これ以降は GlobalFrameAllocator からフレームを確保する
+pub fn init_frame_allocator(frame_allocator: BootInfoFrameAllocator) {
+    *FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
+/// 共有のフレームアロケータを使う FrameAllocator
+pub struct GlobalFrameAllocator;
+
+unsafe impl FrameAllocator<Size4KiB> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        use x86_64::instructions::interrupts;
+
+        // ロック中の割り込みを防止
+        interrupts::without_interrupts(|| {
+            FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+        })
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        use x86_64::instructions::interrupts;
+
+        interrupts::without_interrupts(|| {
+            if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+                unsafe { allocator.deallocate_frame(frame) };
+            }
+        });
+    }
+}
+
+/// どこからも使われなくなったフレームを返却する
+pub fn free_frame(frame: PhysFrame) {
+    unsafe {
+        GlobalFrameAllocator.deallocate_frame(frame);
+    }
+}
+
+/// フレームの参照数を 1 増やす
+pub fn frame_ref_inc(frame: PhysFrame) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        *FRAME_REFCOUNT.lock().entry(frame).or_insert(1) += 1;
+    });
+}
+
+/// フレームの参照数を 1 減らし、残りの参照数を返す
+pub fn frame_ref_dec(frame: PhysFrame) -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut refcount = FRAME_REFCOUNT.lock();
+        match refcount.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                let remaining = *count;
+                if remaining <= 1 {
+                    refcount.remove(&frame);
+                }
+                remaining
+            }
+            None => 0,
+        }
+    })
+}
+
+/// COW で共有しているフレーム frame の内容を new_frame にコピーし、frame の参照数を 1 減らす
+/// 参照数が 1 (他に共有しているページテーブルがない) ならコピーせずに false を返す
+/// 参照数の確認・コピー・減算を 1 つのロックの中で行うので、同じフレームへの書き込みが複数の CPU で同時にフォルトしても、
+/// 最後に残った 1 つはコピーせずにそのフレームを使う
+pub fn copy_shared_frame(frame: PhysFrame, new_frame: PhysFrame) -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut refcount = FRAME_REFCOUNT.lock();
+        let Some(count) = refcount.get_mut(&frame) else {
+            return false;
+        };
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+                phys_to_virt(new_frame.start_address()).as_mut_ptr::<u8>(),
+                4096,
+            );
+        }
+        *count -= 1;
+        if *count <= 1 {
+            refcount.remove(&frame);
+        }
+        true
+    })
+}
+
+/// フレームの参照数
+pub fn frame_ref_count(frame: PhysFrame) -> usize {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        FRAME_REFCOUNT.lock().get(&frame).copied().unwrap_or(1)
+    })
+}
+
+/// 現在のアドレス空間で addr を含むページのフラグを返す
+/// ページが存在しないか、途中の階層で USER_ACCESSIBLE が立っていなければ None
+pub fn user_page_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    use x86_64::registers::control::Cr3;
+
+    let (level_4_table_frame, _) = Cr3::read();
+
+    let table_indexes = [
+        addr.p4_index(), addr.p3_index(), addr.p2_index(), addr.p1_index()
+    ];
+    let mut frame = level_4_table_frame;
+    let mut flags = PageTableFlags::empty();
+
+    for &index in &table_indexes {
+        let table_ptr: *const PageTable = phys_to_virt(frame.start_address()).as_ptr();
+        let table = unsafe { &*table_ptr };
+
+        let entry = &table[index];
+        flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return None;
+        }
+        frame = entry.frame().ok()?;
+    }
+
+    Some(flags)
+}
+
+/// 物理アドレス [phys, phys + size) のデバイスのレジスタを、キャッシュを無効にしてカーネルのページテーブルにマップする
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, &'static str> {
+    use x86_64::instructions::interrupts;
+
+    let start = PhysFrame::<Size4KiB>::containing_address(phys);
+    let end = PhysFrame::<Size4KiB>::containing_address(phys + size.max(1) - 1u64);
+    let pages = end.start_address() - start.start_address() + 4096;
+
+    let virt_start = interrupts::without_interrupts(|| {
+        let mut next = MMIO_NEXT.lock();
+        let virt_start = *next;
+        if virt_start + pages > MMIO_END {
+            return Err("MMIO area exhausted");
+        }
+        *next += pages;
+        Ok(virt_start)
+    })?;
+
+    let offset = physical_memory_offset();
+    let table = unsafe { &mut *phys_to_virt(kernel_pml4().start_address()).as_mut_ptr::<PageTable>() };
+    let mut mapper = unsafe { OffsetPageTable::new(table, offset) };
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH | PageTableFlags::NO_EXECUTE;
+    for (i, frame) in PhysFrame::range_inclusive(start, end).enumerate() {
+        let page = Page::containing_address(VirtAddr::new(virt_start + i as u64 * 4096));
+        unsafe {
+            mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)
+                .map_err(|_| "MMIO map_to failed")?
+                .flush();
+        }
+    }
+
+    Ok(VirtAddr::new(virt_start + (phys - start.start_address())))
+}
+
+/// AP の起動コード用に取っておいた 1 MiB 未満のフレームを返す
+/// 使用可能な 1 MiB 未満のフレームがなければ None
+pub fn find_low_frame() -> Option<PhysFrame> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_ref()?.low_frame
+    })
+}
+
+/// frame を同じ仮想アドレスにカーネルのページテーブルでマップする
+/// すでに同じフレームにマップされていれば何もしない
+pub fn identity_map(frame: PhysFrame) -> Result<(), &'static str> {
+    use x86_64::structures::paging::{ Translate, mapper::TranslateResult };
+
+    let offset = physical_memory_offset();
+    let table = unsafe { &mut *phys_to_virt(kernel_pml4().start_address()).as_mut_ptr::<PageTable>() };
+    let mut mapper = unsafe { OffsetPageTable::new(table, offset) };
+    let page = Page::<Size4KiB>::containing_address(VirtAddr::new(frame.start_address().as_u64()));
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { frame: mapped, .. } if mapped.start_address() == frame.start_address() => Ok(()),
+        TranslateResult::Mapped { .. } => Err("identity page already mapped elsewhere"),
+        _ => unsafe {
+            mapper.map_to(page, frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE, &mut GlobalFrameAllocator)
+                .map_err(|_| "identity map_to failed")?
+                .flush();
+            Ok(())
+        },
+    }
+}
+
+/// 現在のアドレス空間で addr がマップされているか
+/// メモリ初期化前は常に false を返す
+pub fn is_mapped(addr: VirtAddr) -> bool {
+    use x86_64::structures::paging::Translate;
+
+    let Some(&offset) = PHYSICAL_MEMORY_OFFSET.get() else {
+        return false;
+    };
+    let mapper = unsafe { OffsetPageTable::new(active_level_4_table(offset), offset) };
+    mapper.translate_addr(addr).is_some()
+}
+
 /// 与えられたページをフレーム 0xb8000 に試しにマップする
 pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_allocator: &mut impl FrameAllocator<Size4KiB>) {
     use x86_64::structures::paging::PageTableFlags as Flags;
@@ -29,23 +287,54 @@ pub fn create_example_mapping(page: Page, mapper: &mut OffsetPageTable, frame_al
 /// FrameAllcoator
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        // 返却されたフレームがあればそれを使う
+        if let Some(frame) = self.free_list {
+            let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+            self.free_list = (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+/// FrameDeallocator
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        // フレームの先頭に次の空きフレームのアドレスを書いて、リストの先頭につなぐ
+        // フレーム 0 は確保しないので、0 をリストの終端とする
+        let next = self.free_list.map_or(0, |next| next.start_address().as_u64());
+        unsafe {
+            phys_to_virt(frame.start_address()).as_mut_ptr::<u64>().write(next);
+        }
+        self.free_list = Some(frame);
+    }
+}
+
 /// ブートローダのメモリマップから使用可能なフレームを返す
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    free_list: Option<PhysFrame>,   // 返却されたフレームのリスト
+    low_frame: Option<PhysFrame>,   // AP の起動コード用に取っておく 1 MiB 未満のフレーム
 }
 impl BootInfoFrameAllocator {
     /// 渡されたメモリマップから FrameAllocator を作る
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        // フレーム 0 (リアルモードの割り込みベクタ) を除いた、最初の使用可能な 1 MiB 未満のフレーム
+        let low_frame = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .flat_map(|r| r.range.start_addr()..r.range.end_addr().min(LOW_MEMORY_END))
+            .step_by(4096)
+            .find(|&addr| addr != 0)
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            free_list: None,
+            low_frame,
         }
     }
 
@@ -57,7 +346,10 @@ impl BootInfoFrameAllocator {
         // それぞれの領域をアドレス範囲に map で変換する
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
         // フレームの開始アドレスのイテレータへと変換する
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // フレーム 0 は返却されたフレームのリストの終端に使うので、AP の起動コード用のフレームと一緒に除く
+        let low_frame = self.low_frame.map(|frame| frame.start_address().as_u64());
+        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096))
+            .filter(move |&addr| addr != 0 && Some(addr) != low_frame);
         // 開始アドレスから PhysFrame 型を得る
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
     }
@@ -0,0 +1,275 @@
+use super::{ Thread, ThreadState, THREAD_TABLE, NTHREAD, cpu };
+use super::timeline::Timeline;
+use crate::libbackend::lock::IrqMutex;
+use crate::thread::{ MIN_NICE, MAX_NICE };
+use crate::time::{ self, tsc };
+use x86_64::instructions::interrupts;
+
+/// nice 0 の重み
+pub const NICE_0_WEIGHT: u64 = 1024;
+
+/// nice -20 ～ 19 の重み
+/// nice が 1 違うと、CPU 時間の取り分が約 1.25 倍違う
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291,
+    29154, 23254, 18705, 14949, 11916,
+    9548, 7620, 6100, 4904, 3906,
+    3121, 2501, 1991, 1586, 1277,
+    1024, 820, 655, 526, 423,
+    335, 272, 215, 172, 137,
+    110, 87, 70, 56, 45,
+    36, 29, 23, 18, 15,
+];
+
+/// Runnable なスレッドが一巡する時間の目安 (ns)
+pub const SCHED_LATENCY_NS: u64 = 60_000_000;
+
+/// タイムスライスの最小値 (ns)
+/// Runnable なスレッドが多いときは、一巡する時間をこれとスレッド数の積まで延ばす
+pub const MIN_GRANULARITY_NS: u64 = 10_000_000;
+
+/// nice の重み
+pub fn nice_to_weight(nice: i8) -> u64 {
+    NICE_TO_WEIGHT[(nice.clamp(MIN_NICE, MAX_NICE) - MIN_NICE) as usize]
+}
+
+/// ns 実行したときに進む vruntime
+/// 重いスレッドほどゆっくり進むので、多く実行される
+fn weighted_runtime(nanos: u64, weight: u64) -> u64 {
+    (nanos as u128 * NICE_0_WEIGHT as u128 / weight as u128) as u64
+}
+
+/// 重み weight のスレッドのタイムスライス (ns)
+/// 一巡する時間を、待っているスレッドと合わせた重みの比で分ける
+fn time_slice(nr_running: usize, weight: u64, total_weight: u64) -> u64 {
+    let period = SCHED_LATENCY_NS.max(nr_running as u64 * MIN_GRANULARITY_NS);
+    let slice = (period as u128 * weight as u128 / total_weight.max(1) as u128) as u64;
+    slice.max(MIN_GRANULARITY_NS)
+}
+
+/// スレッドごとの実行時間
+#[derive(Clone, Copy)]
+struct Entity {
+    vruntime: u64,
+    weight: u64,
+    exec_start: u64,        // 最後に実行時間を数えた時刻 (ns)
+    slice: u64,             // 今回のタイムスライス (ns)
+    ran: u64,               // 今回のタイムスライスで実行した時間 (ns)
+}
+
+impl Entity {
+    const fn new() -> Self {
+        Entity { vruntime: 0, weight: NICE_0_WEIGHT, exec_start: 0, slice: 0, ran: 0 }
+    }
+}
+
+struct State {
+    timeline: Timeline,
+    entities: [Entity; NTHREAD],
+    queued_weight: u64,     // timeline に入っているスレッドの重みの合計
+    min_vruntime: u64,      // 単調増加する vruntime の基準 (新しいスレッド・起きたスレッドの位置を決める)
+    adopted: bool,          // スケジューラを初期化する前に作ったスレッドを取り込んだか
+}
+
+impl State {
+    /// THREAD_TABLE を持ったまま、Runnable なスレッドを timeline に入れる
+    fn enqueue(&mut self, table: &[Thread; NTHREAD], tid: usize) {
+        if table[tid].state != ThreadState::Runnable {
+            return;
+        }
+        if self.timeline.contains(tid) {
+            self.queued_weight -= self.entities[tid].weight;
+        }
+        self.entities[tid].weight = nice_to_weight(table[tid].nice);
+        self.queued_weight += self.entities[tid].weight;
+        self.timeline.insert(tid, self.entities[tid].vruntime);
+    }
+
+    /// CPU ID cpu で実行できるスレッドのうち、vruntime の最も小さいものを取り出す
+    /// affinity で cpu では実行できないスレッドは timeline に残す
+    /// まだ他の CPU でコンテキストを保存していないスレッドは、保存し終えたときに入れ直すので捨てる
+    fn pick(&mut self, table: &[Thread; NTHREAD], cpu: usize) -> Option<usize> {
+        let runnable = |tid: usize| table[tid].state == ThreadState::Runnable && !table[tid].on_cpu;
+        while let Some(tid) = self.timeline.pop_min_where(|tid| !runnable(tid) || table[tid].can_run_on(cpu)) {
+            self.queued_weight -= self.entities[tid].weight;
+            if runnable(tid) {
+                return Some(tid);
+            }
+        }
+        None
+    }
+
+    /// 実行中のスレッドの、前回数えてからの実行時間を vruntime に足す
+    fn charge(&mut self, tid: usize, now: u64) {
+        let entity = &mut self.entities[tid];
+        let delta = now.saturating_sub(entity.exec_start);
+        entity.exec_start = now;
+        entity.ran += delta;
+        entity.vruntime += weighted_runtime(delta, entity.weight);
+    }
+
+    /// 眠っていたスレッドを min_vruntime の近くに置く
+    /// 眠っていた間の分をまとめて取り返さないよう、遅れは一巡の半分までにする
+    fn place_woken(&mut self, tid: usize) {
+        let floor = self.min_vruntime.saturating_sub(SCHED_LATENCY_NS / 2);
+        let entity = &mut self.entities[tid];
+        entity.vruntime = entity.vruntime.max(floor);
+    }
+}
+
+/// 実行時間を数える時刻 (ns)
+/// TSC を計測していればティックより細かく数える
+fn now() -> u64 {
+    tsc::nanos().unwrap_or_else(time::nanos)
+}
+
+/// 公平配分 (CFS 風) スケジューラ
+/// スレッドごとに nice の重みで割った実行時間 (vruntime) を数え、常に vruntime の最も小さいスレッドを実行する
+/// タイムスライスは Runnable なスレッドの数と重みに合わせて決める
+pub struct Cfs {
+    state: IrqMutex<State>,
+}
+
+impl Cfs {
+    pub fn new() -> Self {
+        Cfs {
+            state: IrqMutex::new(State {
+                timeline: Timeline::new(),
+                entities: [Entity::new(); NTHREAD],
+                queued_weight: 0,
+                min_vruntime: 0,
+                adopted: false,
+            }),
+        }
+    }
+}
+
+impl Default for Cfs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::Scheduler for Cfs {
+    /// スケジューラ
+    /// CPU ごとに呼び、すべての CPU が timeline を共有する
+    fn scheduler(&self) -> ! {
+        super::start_on_this_cpu();
+
+        // スケジューラを初期化する前に作ったスレッドを、最初に開始した CPU で取り込む
+        {
+            let table = THREAD_TABLE.lock();
+            let mut state = self.state.lock();
+            if !state.adopted {
+                state.adopted = true;
+                for tid in 0..NTHREAD {
+                    state.enqueue(&table, tid);
+                }
+            }
+        }
+
+        loop {
+            interrupts::disable();
+
+            let mut table = THREAD_TABLE.lock();
+
+            let next_tid = {
+                let mut state = self.state.lock();
+                let now = now();
+
+                // スレッドからスケジューラに戻ってきた
+                // 実行した分を vruntime に足し、Runnable のまま戻ってきたら timeline に戻す
+                if let Some(tid) = cpu::take_current_tid() {
+                    super::switched_out(&mut table[tid]);
+                    state.charge(tid, now);
+                    state.enqueue(&table, tid);
+                }
+
+                let next_tid = state.pick(&table, cpu::id());
+                if let Some(tid) = next_tid {
+                    let nr_running = state.timeline.len() + 1;
+                    let total_weight = state.queued_weight + state.entities[tid].weight;
+                    let entity = &mut state.entities[tid];
+                    entity.exec_start = now;
+                    entity.ran = 0;
+                    entity.slice = time_slice(nr_running, entity.weight, total_weight);
+                    let vruntime = entity.vruntime;
+                    state.min_vruntime = state.min_vruntime.max(vruntime);
+                }
+                next_tid
+            };
+
+            match next_tid {
+                None => {
+                    // ロックを外してから、割り込みが来るまで待つ
+                    drop(table);
+                    interrupts::enable_and_hlt();
+                    continue;
+                }
+                Some(next_tid) => super::run(table, next_tid),
+            }
+        }
+    }
+
+    /// chan で Sleeping になっているスレッドを Runnable にして、timeline に入れる
+    fn on_wakeup(&self, chan: usize) {
+        let mut table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        for tid in 0..NTHREAD {
+            if table[tid].state == ThreadState::Sleeping && table[tid].chan == Some(chan) {
+                table[tid].state = ThreadState::Runnable;
+                table[tid].chan = None;
+                state.place_woken(tid);
+                state.enqueue(&table, tid);
+            }
+        }
+    }
+
+    /// 新しいスレッドを min_vruntime から始める
+    fn on_ready(&self, tid: usize) {
+        let table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        state.entities[tid] = Entity::new();
+        state.entities[tid].vruntime = state.min_vruntime;
+        state.enqueue(&table, tid);
+    }
+
+    /// nice を変えたスレッドの重みを変える
+    /// 実行中のスレッドは、スケジューラに戻ってきたときに変わる
+    fn on_param_change(&self, tid: usize) {
+        let table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        if state.timeline.contains(tid) {
+            state.enqueue(&table, tid);
+        }
+    }
+
+    /// 実行中のスレッドの実行時間を数え、タイムスライスを使い切ったらプリエンプトする
+    fn on_tick(&self) -> bool {
+        let Some(tid) = cpu::current_tid() else {
+            return false;
+        };
+        let mut state = self.state.lock();
+        state.charge(tid, now());
+        state.entities[tid].ran >= state.entities[tid].slice
+    }
+}
+
+#[test_case]
+fn test_nice_weights() {
+    assert_eq!(nice_to_weight(0), NICE_0_WEIGHT);
+    assert_eq!(nice_to_weight(-20), 88761);
+    assert_eq!(nice_to_weight(19), 15);
+    // nice が低いほど vruntime はゆっくり進む
+    assert!(weighted_runtime(1_000_000, nice_to_weight(-5)) < weighted_runtime(1_000_000, nice_to_weight(5)));
+    assert_eq!(weighted_runtime(1_000_000, NICE_0_WEIGHT), 1_000_000);
+}
+
+#[test_case]
+fn test_time_slice_adapts_to_load() {
+    // 2 つなら一巡の時間を半分ずつ
+    assert_eq!(time_slice(2, NICE_0_WEIGHT, 2 * NICE_0_WEIGHT), SCHED_LATENCY_NS / 2);
+    // 多いときは一巡の時間を延ばし、最小値を下回らない
+    assert_eq!(time_slice(12, NICE_0_WEIGHT, 12 * NICE_0_WEIGHT), MIN_GRANULARITY_NS);
+    assert_eq!(time_slice(2, 15, 15 + 88761), MIN_GRANULARITY_NS);
+}
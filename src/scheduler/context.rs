@@ -15,10 +15,12 @@ pub struct Context {
     pub cs: u64,
     pub ss: u64,
     pub rsp3: u64,
+    pub rip3: u64,
+    pub fpu: u64,       // FPU/SSE/AVX の保存領域 (0 ならカーネル用で保存しない)
 }
 
 impl Context {
-    pub fn new() -> Self {
+    pub const fn new() -> Self {
         Context {
             r15: 0,
             r14: 0,
@@ -32,21 +34,41 @@ impl Context {
             cs: 0,
             ss: 0,
             rsp3: 0,
+            rip3: 0,
+            fpu: 0,
         }
     }
 }
 
 use core::arch::global_asm;
+use crate::fpu;
 
 unsafe extern "C" {
-    pub fn switch_context(old: *mut Context, new: *const Context);
+    fn switch_registers(old: *mut Context, new: *const Context);
 }
 
-// コンテキストスイッチ
+/// コンテキストスイッチ
+/// 保存領域を持つコンテキストは FPU/SSE/AVX の状態も保存・復元する
+///
+/// # Safety
+/// old と new は有効な Context を指し、割り込みを禁止した状態で呼ぶこと
+pub unsafe fn switch_context(old: *mut Context, new: *const Context) {
+    unsafe {
+        if (*old).fpu != 0 {
+            fpu::save((*old).fpu);
+        }
+        if (*new).fpu != 0 {
+            fpu::restore((*new).fpu);
+        }
+        switch_registers(old, new);
+    }
+}
+
+// 汎用レジスタのコンテキストスイッチ
 global_asm!(
 r#"
-.globl switch_context
-switch_context:
+.globl switch_registers
+switch_registers:
     # 現在のコンテキストを保存
     mov [rdi + 0], r15
     mov [rdi + 8], r14
@@ -0,0 +1,473 @@
+use super::{ Thread, ThreadState, THREAD_TABLE, NTHREAD, SCHEDULER, cpu };
+use super::run_queue::RunQueues;
+use super::timeline::Timeline;
+use crate::libbackend::lock::IrqMutex;
+use crate::time::{ self, tsc };
+use x86_64::instructions::interrupts;
+
+/// 周期的なリアルタイムスレッドのパラメータ (ns)
+/// period ごとに runtime だけ実行でき、各周期の開始から deadline までに終える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineParams {
+    pub runtime: u64,
+    pub deadline: u64,
+    pub period: u64,
+}
+
+impl DeadlineParams {
+    /// 使用率 (UTIL_ONE を 1 とする固定小数点)
+    fn utilization(&self) -> u64 {
+        ((self.runtime as u128 * UTIL_ONE as u128) / self.period as u128) as u64
+    }
+
+    /// 密度 runtime / min(deadline, period) (UTIL_ONE を 1 とする固定小数点、切り上げ)
+    fn density(&self) -> u64 {
+        let window = self.deadline.min(self.period);
+        (self.runtime as u128 * UTIL_ONE as u128).div_ceil(window as u128) as u64
+    }
+}
+
+/// set_deadline のエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeadlineError {
+    InvalidParams,      // runtime ≦ deadline ≦ period を満たさない
+    PeriodTooShort,     // period が MIN_PERIOD_NS より短い
+    NoSuchThread,
+    NotSchedulable,     // 受け入れ制御で断った
+}
+
+/// 使用率の固定小数点の 1
+const UTIL_ONE: u64 = 1 << 20;
+
+/// リアルタイムスレッドに割り当てられる CPU 時間の割合 (%)
+/// 残りはベストエフォートのスレッドのために空けておく
+pub const RT_BANDWIDTH_PERCENT: u64 = 95;
+
+/// パラメータの周期の最小値 (ns)
+/// 実行時間はタイマ割り込みで数えるので、ティックより短い周期は守れない
+pub const MIN_PERIOD_NS: u64 = 1_000_000;
+
+/// スレッドを周期的なリアルタイムスレッドにする
+/// None ならベストエフォートのスレッドに戻す
+/// runtime ≦ deadline ≦ period でなければならず、admissible を満たさなければ受け入れない
+/// Edf スケジューラを使っているときだけ効果がある
+pub fn set_deadline(tid: usize, params: Option<DeadlineParams>) -> Result<(), DeadlineError> {
+    if let Some(params) = params {
+        if params.runtime == 0 || params.runtime > params.deadline || params.deadline > params.period {
+            return Err(DeadlineError::InvalidParams);
+        }
+        if params.period < MIN_PERIOD_NS {
+            return Err(DeadlineError::PeriodTooShort);
+        }
+    }
+
+    {
+        let mut table = THREAD_TABLE.lock();
+        if table.get(tid).is_none_or(|thread| thread.state == ThreadState::Unused) {
+            return Err(DeadlineError::NoSuchThread);
+        }
+
+        // 受け入れ制御
+        if let Some(params) = params {
+            let others = table.iter()
+                .filter(|thread| thread.tid != tid && thread.state != ThreadState::Unused)
+                .filter_map(|thread| thread.deadline);
+            if !admissible(cpu::count(), others.chain(core::iter::once(params))) {
+                return Err(DeadlineError::NotSchedulable);
+            }
+        }
+        table[tid].deadline = params;
+    }
+
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.on_param_change(tid);
+    }
+    Ok(())
+}
+
+/// cpus 個の CPU のグローバル EDF で、tasks のリアルタイムスレッドをすべてデッドラインまでに実行できるか
+/// 使用率の合計が CPU 数 × RT_BANDWIDTH_PERCENT % 以下で、
+/// 密度 δ が GFB の条件 Σδ ≦ m − (m − 1)・max δ (m は CPU 数) を満たさなければならない
+fn admissible(cpus: usize, tasks: impl Iterator<Item = DeadlineParams>) -> bool {
+    let (mut utilization, mut density, mut max_density) = (0, 0, 0);
+    for params in tasks {
+        utilization += params.utilization();
+        density += params.density();
+        max_density = max_density.max(params.density());
+    }
+    let m = cpus as u64;
+    utilization <= m * UTIL_ONE * RT_BANDWIDTH_PERCENT / 100
+        && density + (m - 1) * max_density <= m * UTIL_ONE
+}
+
+/// リアルタイムスレッドの今の周期の状態
+#[derive(Clone, Copy)]
+struct Job {
+    params: Option<DeadlineParams>,     // スレッドのパラメータの写し (None ならベストエフォート)
+    release: u64,           // 今の周期の開始時刻
+    abs_deadline: u64,      // 今の周期の絶対デッドライン
+    budget: u64,            // 今の周期の残りの実行時間
+    exec_start: u64,        // 最後に実行時間を数えた時刻
+    throttled: bool,        // 次の周期まで実行しない
+    preempted: bool,        // on_tick でプリエンプトした (yield と区別する)
+    missed: bool,           // 今の周期でデッドラインミスを数えた
+}
+
+impl Job {
+    const fn new() -> Self {
+        Job {
+            params: None,
+            release: 0,
+            abs_deadline: 0,
+            budget: 0,
+            exec_start: 0,
+            throttled: false,
+            preempted: false,
+            missed: false,
+        }
+    }
+
+    /// start から新しい周期を始める
+    fn replenish(&mut self, start: u64) {
+        let Some(params) = self.params else {
+            return;
+        };
+        self.release = start;
+        self.abs_deadline = start + params.deadline;
+        self.budget = params.runtime;
+        self.throttled = false;
+        self.missed = false;
+    }
+
+    /// 次の周期の開始時刻
+    fn next_release(&self) -> u64 {
+        self.release + self.params.map_or(0, |params| params.period)
+    }
+
+    /// 前回数えてからの実行時間を残りの実行時間から引く
+    fn charge(&mut self, now: u64) {
+        let delta = now.saturating_sub(self.exec_start);
+        self.exec_start = now;
+        self.budget = self.budget.saturating_sub(delta);
+    }
+}
+
+struct State {
+    deadline_queue: Timeline,       // Runnable なリアルタイムスレッド (絶対デッドラインの順)
+    best_effort: RunQueues<1>,      // Runnable なベストエフォートのスレッド
+    jobs: [Job; NTHREAD],
+    unreported: [u64; NTHREAD],     // スケジューラが Thread に数え終えていないデッドラインミス
+    adopted: bool,                  // スケジューラを初期化する前に作ったスレッドを取り込んだか
+}
+
+impl State {
+    /// THREAD_TABLE を持ったまま、Runnable なスレッドをクラスに合った列に入れる
+    /// 次の周期を待っているリアルタイムスレッドは、on_tick で周期が来たときに入れる
+    fn enqueue(&mut self, table: &[Thread; NTHREAD], tid: usize) {
+        if table[tid].state != ThreadState::Runnable {
+            return;
+        }
+        match self.jobs[tid].params {
+            Some(_) if self.jobs[tid].throttled => {}
+            Some(_) => self.deadline_queue.insert(tid, self.jobs[tid].abs_deadline),
+            None => self.best_effort.push(tid, 0),
+        }
+    }
+
+    /// スレッドのパラメータの写しを更新する
+    /// リアルタイムスレッドになったときは、今から新しい周期を始める
+    fn update_params(&mut self, table: &[Thread; NTHREAD], tid: usize, now: u64) {
+        let params = table[tid].deadline;
+        if self.jobs[tid].params == params {
+            return;
+        }
+        self.deadline_queue.remove(tid);
+        self.jobs[tid] = Job::new();
+        self.jobs[tid].params = params;
+        self.jobs[tid].replenish(now);
+    }
+
+    /// 起きたリアルタイムスレッドの周期を決める
+    /// 今のデッドラインまでに残りの実行時間を使うと使用率を超えるなら、今から新しい周期を始める
+    fn wake(&mut self, tid: usize, now: u64) {
+        let job = &mut self.jobs[tid];
+        let Some(params) = job.params else {
+            return;
+        };
+        if job.throttled {
+            return;
+        }
+        let remaining = job.abs_deadline.saturating_sub(now);
+        if remaining == 0 || job.budget as u128 * params.period as u128 > remaining as u128 * params.runtime as u128 {
+            job.replenish(now);
+        }
+    }
+
+    /// デッドラインミスを数える (周期ごとに 1 回)
+    fn miss(&mut self, tid: usize) {
+        if !self.jobs[tid].missed {
+            self.jobs[tid].missed = true;
+            self.unreported[tid] += 1;
+        }
+    }
+
+    /// 次の周期を待っているリアルタイムスレッドのうち、周期が来たものを列に戻す
+    /// 実行時間を使い切って待っていたスレッドは、前の周期のデッドラインに間に合わなかった
+    /// 戻したスレッドがあれば true を返す
+    fn release_due(&mut self, now: u64) -> bool {
+        let mut released = false;
+        for tid in 0..NTHREAD {
+            let job = self.jobs[tid];
+            if !job.throttled || job.next_release() > now {
+                continue;
+            }
+            if job.budget == 0 {
+                self.miss(tid);
+            }
+            // 周期に遅れているときは今から始める
+            let start = if now - job.next_release() < job.params.map_or(0, |params| params.period) {
+                job.next_release()
+            } else {
+                now
+            };
+            self.jobs[tid].replenish(start);
+            self.deadline_queue.insert(tid, self.jobs[tid].abs_deadline);
+            released = true;
+        }
+        released
+    }
+
+    /// CPU ID cpu で次に実行するスレッドを取り出す
+    /// リアルタイムスレッドを絶対デッドラインの順に選び、いなければベストエフォートのスレッドを選ぶ
+    /// affinity で cpu では実行できないスレッドは列に残す
+    /// まだ他の CPU でコンテキストを保存していないスレッドは、保存し終えたときに入れ直すので捨てる
+    fn pick(&mut self, table: &[Thread; NTHREAD], cpu: usize, now: u64) -> Option<usize> {
+        let jobs = &self.jobs;
+        let runnable = |tid: usize| {
+            table[tid].state == ThreadState::Runnable && !table[tid].on_cpu && jobs[tid].params.is_some()
+        };
+        while let Some(tid) = self.deadline_queue.pop_min_where(|tid| !runnable(tid) || table[tid].can_run_on(cpu)) {
+            if !runnable(tid) {
+                continue;
+            }
+            if self.jobs[tid].abs_deadline < now {
+                self.miss(tid);
+            }
+            return Some(tid);
+        }
+        loop {
+            let tid = self.best_effort.pop_runnable(table, cpu)?;
+            if self.jobs[tid].params.is_none() {
+                return Some(tid);
+            }
+        }
+    }
+}
+
+/// 実行時間を数える時刻 (ns)
+/// TSC を計測していればティックより細かく数える
+fn now() -> u64 {
+    tsc::nanos().unwrap_or_else(time::nanos)
+}
+
+/// EDF (Earliest Deadline First) スケジューラ
+/// set_deadline でパラメータを与えたリアルタイムスレッドを、絶対デッドラインの早い順に先に実行する
+/// リアルタイムスレッドは周期ごとに runtime だけ実行でき、使い切るか yield すると次の周期まで待つ
+/// 周期的なスレッドは、周期の仕事を終えるたびに yield する
+/// リアルタイムスレッドが実行できないときは、ベストエフォートのスレッドをラウンドロビンで実行する
+pub struct Edf {
+    state: IrqMutex<State>,
+}
+
+impl Edf {
+    pub fn new() -> Self {
+        Edf {
+            state: IrqMutex::new(State {
+                deadline_queue: Timeline::new(),
+                best_effort: RunQueues::new(),
+                jobs: [Job::new(); NTHREAD],
+                unreported: [0; NTHREAD],
+                adopted: false,
+            }),
+        }
+    }
+}
+
+impl Default for Edf {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::Scheduler for Edf {
+    /// スケジューラ
+    /// CPU ごとに呼び、すべての CPU が列を共有する (グローバル EDF)
+    fn scheduler(&self) -> ! {
+        super::start_on_this_cpu();
+
+        // スケジューラを初期化する前に作ったスレッドを、最初に開始した CPU で取り込む
+        {
+            let table = THREAD_TABLE.lock();
+            let mut state = self.state.lock();
+            if !state.adopted {
+                state.adopted = true;
+                let now = now();
+                for tid in 0..NTHREAD {
+                    state.update_params(&table, tid, now);
+                    state.enqueue(&table, tid);
+                }
+            }
+        }
+
+        loop {
+            interrupts::disable();
+
+            let mut table = THREAD_TABLE.lock();
+
+            let next_tid = {
+                let mut state = self.state.lock();
+                let now = now();
+
+                // スレッドからスケジューラに戻ってきた
+                if let Some(tid) = cpu::take_current_tid() {
+                    super::switched_out(&mut table[tid]);
+                    let job = &mut state.jobs[tid];
+                    if job.params.is_some() {
+                        job.charge(now);
+                        // 実行時間を使い切ったか、プリエンプトされずに yield したら次の周期まで待つ
+                        if table[tid].state == ThreadState::Runnable && (job.budget == 0 || !job.preempted) {
+                            job.throttled = true;
+                        }
+                        job.preempted = false;
+                    }
+                    state.update_params(&table, tid, now);
+                    state.enqueue(&table, tid);
+                }
+
+                // on_tick で見つけたデッドラインミスを Thread に数える (ps で表示する)
+                for tid in 0..NTHREAD {
+                    table[tid].deadline_misses += state.unreported[tid];
+                    state.unreported[tid] = 0;
+                }
+
+                let next_tid = state.pick(&table, cpu::id(), now);
+                if let Some(tid) = next_tid {
+                    state.jobs[tid].exec_start = now;
+                }
+                next_tid
+            };
+
+            match next_tid {
+                None => {
+                    // ロックを外してから、割り込みが来るまで待つ
+                    drop(table);
+                    interrupts::enable_and_hlt();
+                    continue;
+                }
+                Some(next_tid) => super::run(table, next_tid),
+            }
+        }
+    }
+
+    /// chan で Sleeping になっているスレッドを Runnable にして、クラスに合った列に入れる
+    fn on_wakeup(&self, chan: usize) {
+        let mut table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        let now = now();
+        for tid in 0..NTHREAD {
+            if table[tid].state == ThreadState::Sleeping && table[tid].chan == Some(chan) {
+                table[tid].state = ThreadState::Runnable;
+                table[tid].chan = None;
+                state.update_params(&table, tid, now);
+                state.wake(tid, now);
+                state.enqueue(&table, tid);
+            }
+        }
+    }
+
+    fn on_ready(&self, tid: usize) {
+        let table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        state.jobs[tid] = Job::new();
+        state.update_params(&table, tid, now());
+        state.enqueue(&table, tid);
+    }
+
+    /// set_deadline でクラスやパラメータが変わったら、列を移す
+    /// 実行中のスレッドは、スケジューラに戻ってきたときに変わる
+    fn on_param_change(&self, tid: usize) {
+        let table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        if table[tid].state == ThreadState::Runnable && !table[tid].on_cpu {
+            state.update_params(&table, tid, now());
+            state.enqueue(&table, tid);
+        }
+    }
+
+    /// 次の周期が来たリアルタイムスレッドを列に戻し、実行中のスレッドをプリエンプトするかを決める
+    /// リアルタイムスレッドは、実行時間を使い切るか、よりデッドラインの早いスレッドが待っていればプリエンプトする
+    /// ベストエフォートのスレッドは毎ティックプリエンプトする
+    fn on_tick(&self) -> bool {
+        let now = now();
+        let mut state = self.state.lock();
+        let released = state.release_due(now);
+
+        let Some(tid) = cpu::current_tid() else {
+            return released;
+        };
+        if state.jobs[tid].params.is_none() {
+            return true;
+        }
+
+        state.jobs[tid].charge(now);
+        if now > state.jobs[tid].abs_deadline {
+            state.miss(tid);
+        }
+        let earlier_waiting = state.deadline_queue
+            .peek_min()
+            .is_some_and(|(_, deadline)| deadline < state.jobs[tid].abs_deadline);
+        let preempt = state.jobs[tid].budget == 0 || earlier_waiting;
+        state.jobs[tid].preempted = preempt;
+        preempt
+    }
+}
+
+#[test_case]
+fn test_job_replenish_and_charge() {
+    let mut job = Job::new();
+    job.params = Some(DeadlineParams { runtime: 2_000_000, deadline: 5_000_000, period: 10_000_000 });
+    job.replenish(100);
+    assert_eq!(job.abs_deadline, 5_000_100);
+    assert_eq!(job.next_release(), 10_000_100);
+
+    job.exec_start = 100;
+    job.charge(1_000_100);
+    assert_eq!(job.budget, 1_000_000);
+    job.charge(5_000_000);
+    assert_eq!(job.budget, 0);
+}
+
+#[test_case]
+fn test_utilization() {
+    let half = DeadlineParams { runtime: 5, deadline: 10, period: 10 };
+    assert_eq!(half.utilization(), UTIL_ONE / 2);
+    let constrained = DeadlineParams { runtime: 5, deadline: 10, period: 100 };
+    assert_eq!(constrained.density(), UTIL_ONE / 2);
+}
+
+#[test_case]
+fn test_admissible_uses_density() {
+    // 使用率は 5% ずつでも、密度は 1 ずつなので 2 つは受け入れられない
+    let tight = DeadlineParams { runtime: 5_000_000, deadline: 5_000_000, period: 100_000_000 };
+    for cpus in 1..=2 {
+        assert!(admissible(cpus, [tight].into_iter()));
+        assert!(!admissible(cpus, [tight, tight].into_iter()));
+    }
+
+    let light = DeadlineParams { runtime: 2_000_000, deadline: 10_000_000, period: 10_000_000 };
+    assert!(admissible(1, [light; 4].into_iter()));
+    assert!(!admissible(1, [light; 5].into_iter()));
+    // 使用率の合計が RT_BANDWIDTH_PERCENT % を超える
+    let heavy = DeadlineParams { runtime: 96, deadline: 100, period: 100 };
+    assert!(!admissible(1, [heavy].into_iter()));
+}
@@ -0,0 +1,249 @@
+use super::{ Thread, ThreadState, THREAD_TABLE, NTHREAD, cpu };
+use super::run_queue::RunQueues;
+use crate::libbackend::lock::IrqMutex;
+use crate::time;
+use x86_64::instructions::interrupts;
+
+/// 段階数
+pub const NLEVEL: usize = 4;
+
+/// 段階ごとのタイムクォンタム (ティック)
+/// 段階 0 が最も優先度が高く、下の段階ほど長く実行できる
+pub const QUANTUM_TICKS: [u64; NLEVEL] = [1, 2, 4, 8];
+
+/// すべてのスレッドを段階 0 に戻す間隔 (ティック)
+/// 下の段階に落ちたスレッドも、この間隔で少なくとも一度は実行される
+pub const BOOST_INTERVAL_TICKS: u64 = time::TICK_HZ;
+
+/// スレッドごとの段階とクォンタムの消費
+/// 添字は Thread ID
+struct Accounting {
+    level: [usize; NTHREAD],
+    used: [u64; NTHREAD],       // 今の段階で使ったティック数
+}
+
+impl Accounting {
+    const fn new() -> Self {
+        Accounting {
+            level: [0; NTHREAD],
+            used: [0; NTHREAD],
+        }
+    }
+
+    /// 新しいスレッドを段階 0 から始める
+    fn reset(&mut self, tid: usize) {
+        self.level[tid] = 0;
+        self.used[tid] = 0;
+    }
+
+    /// 実行中のスレッドに 1 ティックを数える
+    /// クォンタムを使い切ったら 1 段階下げて true を返す
+    fn tick(&mut self, tid: usize) -> bool {
+        self.used[tid] += 1;
+        if self.used[tid] < QUANTUM_TICKS[self.level[tid]] {
+            return false;
+        }
+        self.level[tid] = (self.level[tid] + 1).min(NLEVEL - 1);
+        self.used[tid] = 0;
+        true
+    }
+
+    /// クォンタムを使い切る前に Sleeping になったスレッドを 1 段階上げる
+    fn blocked(&mut self, tid: usize) {
+        if self.used[tid] < QUANTUM_TICKS[self.level[tid]] {
+            self.level[tid] = self.level[tid].saturating_sub(1);
+        }
+        self.used[tid] = 0;
+    }
+
+    /// すべてのスレッドを段階 0 に戻す
+    fn boost(&mut self) {
+        self.level = [0; NTHREAD];
+        self.used = [0; NTHREAD];
+    }
+}
+
+struct State {
+    run_queues: RunQueues<NLEVEL>,
+    accounting: Accounting,
+    last_boost: u64,            // 最後に段階 0 に戻したティック
+}
+
+impl State {
+    /// 実行中のスレッド tid に 1 ティックを数え、プリエンプトするなら true を返す
+    /// クォンタムが残っていても、tid より上の段階の列にスレッドが待っていれば譲る
+    fn tick(&mut self, tid: usize) -> bool {
+        if self.accounting.tick(tid) {
+            return true;
+        }
+        self.run_queues.highest_level().is_some_and(|level| level < self.accounting.level[tid])
+    }
+}
+
+/// 多段フィードバックキュー (MLFQ) スケジューラ
+/// 新しいスレッドは段階 0 から始め、クォンタムを使い切るたびに下の段階に落とす
+/// クォンタムを使い切る前に眠ったスレッドは上の段階に戻すので、対話的なスレッドが先に動く
+/// BOOST_INTERVAL_TICKS ごとにすべてのスレッドを段階 0 に戻し、下の段階のスレッドが飢えないようにする
+pub struct Mlfq {
+    state: IrqMutex<State>,
+}
+
+impl Mlfq {
+    pub fn new() -> Self {
+        Mlfq {
+            state: IrqMutex::new(State {
+                run_queues: RunQueues::new(),
+                accounting: Accounting::new(),
+                last_boost: 0,
+            }),
+        }
+    }
+
+    /// THREAD_TABLE を持ったまま、Runnable なスレッドを今の段階の列に入れる
+    /// ロックは THREAD_TABLE → state の順に取る
+    fn enqueue(state: &mut State, table: &[Thread; NTHREAD], tid: usize) {
+        if table[tid].state == ThreadState::Runnable {
+            let level = state.accounting.level[tid];
+            state.run_queues.push(tid, level);
+        }
+    }
+}
+
+impl Default for Mlfq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::Scheduler for Mlfq {
+    /// スケジューラ
+    /// CPU ごとに呼び、すべての CPU が段階ごとの列を共有する
+    fn scheduler(&self) -> ! {
+        super::start_on_this_cpu();
+
+        // スケジューラを初期化する前に作ったスレッドを取り込む
+        {
+            let table = THREAD_TABLE.lock();
+            let mut state = self.state.lock();
+            for tid in 0..NTHREAD {
+                Self::enqueue(&mut state, &table, tid);
+            }
+        }
+
+        loop {
+            interrupts::disable();
+
+            let mut table = THREAD_TABLE.lock();
+
+            let next_tid = {
+                let mut state = self.state.lock();
+
+                // スレッドからスケジューラに戻ってきた
+                // Sleeping で戻ってきたら段階を上げ、Runnable のまま戻ってきたら今の段階の列の末尾に戻す
+                if let Some(tid) = cpu::take_current_tid() {
+                    super::switched_out(&mut table[tid]);
+                    if table[tid].state == ThreadState::Sleeping {
+                        state.accounting.blocked(tid);
+                    }
+                    Self::enqueue(&mut state, &table, tid);
+                }
+
+                state.run_queues.pop_runnable(&table, cpu::id())
+            };
+
+            match next_tid {
+                None => {
+                    // ロックを外してから、割り込みが来るまで待つ
+                    drop(table);
+                    interrupts::enable_and_hlt();
+                    continue;
+                }
+                Some(next_tid) => super::run(table, next_tid),
+            }
+        }
+    }
+
+    /// chan で Sleeping になっているスレッドを Runnable にして、今の段階の列に入れる
+    fn on_wakeup(&self, chan: usize) {
+        let mut table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        for tid in 0..NTHREAD {
+            if table[tid].state == ThreadState::Sleeping && table[tid].chan == Some(chan) {
+                table[tid].state = ThreadState::Runnable;
+                table[tid].chan = None;
+                Self::enqueue(&mut state, &table, tid);
+            }
+        }
+    }
+
+    /// 新しいスレッドを段階 0 の列に入れる
+    fn on_ready(&self, tid: usize) {
+        let table = THREAD_TABLE.lock();
+        let mut state = self.state.lock();
+        state.accounting.reset(tid);
+        Self::enqueue(&mut state, &table, tid);
+    }
+
+    /// 実行中のスレッドのクォンタムを数え、使い切るか上の段階のスレッドが待っていればプリエンプトする
+    /// BOOST_INTERVAL_TICKS ごとにすべてのスレッドを段階 0 に戻す
+    fn on_tick(&self) -> bool {
+        let now = time::ticks();
+        let mut state = self.state.lock();
+
+        if now.saturating_sub(state.last_boost) >= BOOST_INTERVAL_TICKS {
+            state.last_boost = now;
+            state.accounting.boost();
+            state.run_queues.move_all_to(0);
+            return true;
+        }
+
+        match cpu::current_tid() {
+            Some(tid) => state.tick(tid),
+            None => false,
+        }
+    }
+}
+
+#[test_case]
+fn test_mlfq_demotes_after_quantum() {
+    let mut accounting = Accounting::new();
+    accounting.reset(1);
+    for level in 0..NLEVEL {
+        for _ in 1..QUANTUM_TICKS[level] {
+            assert!(!accounting.tick(1));
+        }
+        assert!(accounting.tick(1));
+        assert_eq!(accounting.level[1], (level + 1).min(NLEVEL - 1));
+    }
+}
+
+#[test_case]
+fn test_mlfq_boosts_early_blockers() {
+    let mut accounting = Accounting::new();
+    accounting.level[2] = 3;
+    accounting.blocked(2);
+    assert_eq!(accounting.level[2], 2);
+
+    accounting.boost();
+    assert_eq!(accounting.level[2], 0);
+    accounting.blocked(2);
+    assert_eq!(accounting.level[2], 0);
+}
+
+#[test_case]
+fn test_mlfq_preempts_for_higher_level() {
+    let mut state = State {
+        run_queues: RunQueues::new(),
+        accounting: Accounting::new(),
+        last_boost: 0,
+    };
+    state.accounting.level[1] = 2;
+    state.accounting.level[2] = 2;
+    state.run_queues.push(2, 2);
+    // 同じ段階のスレッドが待っていても、クォンタムが残っていれば続ける
+    assert!(!state.tick(1));
+
+    state.run_queues.push(3, 1);
+    assert!(state.tick(1));
+    assert_eq!(state.accounting.level[1], 2);
+}
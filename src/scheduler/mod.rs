@@ -1,21 +1,162 @@
-use crate::thread::{ Thread, ThreadState, THREAD_TABLE, NTHREAD };
-use crate::cpu;
+use crate::thread::{ kthread, Thread, ThreadState, THREAD_TABLE, NTHREAD, NPRIORITY, MIN_NICE, MAX_NICE };
+use crate::thread::uprocess;
+use crate::{ cpu, gdt };
+use crate::libbackend::lock::IrqMutexGuard;
+use context::{ Context, switch_context };
 use conquer_once::spin::OnceCell;
 use alloc::boxed::Box;
+use core::sync::atomic::{ AtomicBool, Ordering };
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts;
 
+pub mod cfs;
 pub mod context;
+pub mod edf;
+pub mod mlfq;
+pub mod priority;
 pub mod round_robin;
+mod run_queue;
+mod timeline;
 
 pub static SCHEDULER: OnceCell<Box<dyn Scheduler + Send + Sync>> = OnceCell::uninit();
-pub static mut SCHEDULER_STARTED: bool = false;
+/// いずれかの CPU でスケジューラを開始したか
+pub static SCHEDULER_STARTED: AtomicBool = AtomicBool::new(false);
 
 pub fn init(scheduler: Box<dyn Scheduler + Send + Sync>) {
     SCHEDULER.init_once(|| scheduler);
 }
 
+/// スケジューラ
+/// scheduler は実行するスレッドの選び方を決め、スレッドからスケジューラへの戻り方は共通の既定の実装を使う
 pub trait Scheduler: Send + Sync {
     fn scheduler(&self) -> !;
-    fn on_yield(&self);
+    fn on_wakeup(&self, chan: usize);
+
+    /// 作成したスレッドを Runnable として受け取る
+    /// THREAD_TABLE のロックを持たずに呼ぶ
+    fn on_ready(&self, _tid: usize) {}
+
+    /// set_priority・set_nice・set_affinity・edf::set_deadline でスレッドのスケジューリングのパラメータを変えた
+    /// THREAD_TABLE のロックを持たずに呼ぶ
+    fn on_param_change(&self, _tid: usize) {}
+
+    /// タイマ割り込みごとに、割り込まれた CPU で呼ぶ
+    /// true を返すと実行中のスレッドをプリエンプトする
+    fn on_tick(&self) -> bool {
+        true
+    }
+
+    /// スレッドからスケジューラに戻る
+    fn on_yield(&self) {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let mut table = THREAD_TABLE.lock();
+
+        let Some(current_tid) = cpu::current_tid() else {
+            drop(table);
+            if were_enabled {
+                interrupts::enable();
+            }
+            return;
+        };
+        // sleep の途中で割り込まれた場合は、sleep がそのままスケジューラに戻る
+        if table[current_tid].state != ThreadState::Running {
+            drop(table);
+            if were_enabled {
+                interrupts::enable();
+            }
+            return;
+        }
+
+        let (old_context, new_context) = {
+            // Runnable に変更
+            table[current_tid].state = ThreadState::Runnable;
+
+            // スケジューラへコンテキストスイッチ
+            let old_context = &mut table[current_tid].context as *mut Context;
+            let new_context = cpu::scheduler_context() as *const Context;
+
+            drop(table);
+
+            (old_context, new_context)
+        };
+        unsafe {
+            switch_context(old_context, new_context);
+        }
+
+        // 再びスケジュールされたら、割り込みの状態を戻す
+        if were_enabled {
+            interrupts::enable();
+        }
+    }
+
+    /// スレッドを終了してスケジューラに戻る
+    /// ユーザスレッドの後始末は wait で回収する側が、カーネルスレッドはスケジューラが行う
+    fn on_exit(&self) -> ! {
+        interrupts::disable();
+
+        let mut table = THREAD_TABLE.lock();
+
+        let current_tid = cpu::current_tid().expect("exit without a running thread");
+
+        let (old_context, new_context) = {
+            // Zombie に変更
+            table[current_tid].state = ThreadState::Zombie;
+
+            // スケジューラへコンテキストスイッチ
+            let old_context = &mut table[current_tid].context as *mut Context;
+            let new_context = cpu::scheduler_context() as *const Context;
+
+            drop(table);
+
+            (old_context, new_context)
+        };
+        unsafe {
+            switch_context(old_context, new_context);
+        }
+
+        unreachable!("zombie thread was scheduled");
+    }
+
+    /// スレッドを chan で Sleeping にしてスケジューラに戻る
+    /// Sleeping にしてから release で呼び出し元のロックを外すので、wakeup を取りこぼさない
+    fn on_sleep(&self, chan: usize, release: &mut dyn FnMut()) {
+        let were_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        let (old_context, new_context) = {
+            let mut table = THREAD_TABLE.lock();
+
+            let current_tid = cpu::current_tid().expect("sleep without a running thread");
+
+            // Sleeping に変更
+            table[current_tid].state = ThreadState::Sleeping;
+            table[current_tid].chan = Some(chan);
+
+            // スケジューラへコンテキストスイッチ
+            let old_context = &mut table[current_tid].context as *mut Context;
+            let new_context = cpu::scheduler_context() as *const Context;
+
+            (old_context, new_context)
+        };
+
+        // 呼び出し元のロックを外す
+        // IrqMutex のガードなら割り込みが有効に戻るが、その間のタイマ割り込みは Sleeping を見て何もしない
+        release();
+        let released_enabled = interrupts::are_enabled();
+        interrupts::disable();
+
+        // ここまでに wakeup されていれば Runnable になっており、スケジューラがすぐに戻してくる
+        unsafe {
+            switch_context(old_context, new_context);
+        }
+
+        // 再びスケジュールされたら、呼び出し元のロックを外した後の割り込みの状態に戻す
+        if were_enabled || released_enabled {
+            interrupts::enable();
+        }
+    }
 }
 
 fn get_scheduler() -> &'static dyn Scheduler {
@@ -31,3 +172,208 @@ pub fn scheduler() -> ! {
 pub fn yield_from_context() {
     get_scheduler().on_yield();
 }
+
+/// 実行中のスレッドを Zombie にしてスケジューラに戻る
+pub fn exit_from_context() -> ! {
+    get_scheduler().on_exit();
+}
+
+/// 実行中のスレッドを chan で Sleeping にしてスケジューラに戻る
+/// guard は THREAD_TABLE をロックしてから外すので、guard を持ったまま条件を確かめてから呼べば
+/// その後の wakeup を取りこぼさない
+/// 起こされたら guard のロックを取り直し、条件を確かめ直すこと
+pub fn sleep<G>(chan: usize, guard: G) {
+    let mut guard = Some(guard);
+    get_scheduler().on_sleep(chan, &mut || drop(guard.take()));
+}
+
+/// タイマ割り込みハンドラから呼び、実行中のスレッドをプリエンプトするかを返す
+pub fn tick() -> bool {
+    get_scheduler().on_tick()
+}
+
+/// chan で Sleeping になっているスレッドをすべて Runnable にする
+/// 割り込みハンドラからも呼べる
+pub fn wakeup(chan: usize) {
+    get_scheduler().on_wakeup(chan);
+}
+
+/// スレッドからスケジューラに戻ってきたときの後処理
+/// コンテキストを保存し終えたので他の CPU でも実行できるようにし、切り替え回数を数える
+/// 終了したカーネルスレッドはここで回収する (カーネルスタックは run で解放する)
+fn switched_out(thread: &mut Thread) {
+    thread.on_cpu = false;
+    if thread.state == ThreadState::Runnable {
+        thread.involuntary_switches += 1;
+    } else {
+        thread.voluntary_switches += 1;
+    }
+    kthread::reap(thread);
+}
+
+/// この CPU でスケジューラを開始したことを記録する
+/// 各 Scheduler::scheduler の最初に呼ぶ
+fn start_on_this_cpu() {
+    if !cpu::start_scheduler() {
+        panic!("Scheduler already started on CPU {}", cpu::id());
+    }
+    SCHEDULER_STARTED.store(true, Ordering::Relaxed);
+}
+
+/// 選んだスレッドをこの CPU で実行し、スレッドがスケジューラに戻ってくるまで待つ
+/// 割り込みを禁止した状態で呼び、table のロックは切り替える前に外す
+fn run(mut table: IrqMutexGuard<'_, [Thread; NTHREAD]>, tid: usize) {
+    let (old_context, new_context, pid) = {
+        // スレッド状態を更新
+        table[tid].state = ThreadState::Running;
+        table[tid].on_cpu = true;
+        table[tid].last_cpu = Some(cpu::id());
+
+        // CPU で実行中のスレッド ID を更新
+        cpu::set_current_tid(Some(tid));
+
+        // Ring 3 からの割り込み・syscall で使うカーネルスタックを切り替え
+        gdt::set_kernel_stack(VirtAddr::new(table[tid].kstack));
+
+        let old_context = cpu::scheduler_context();
+        let new_context = &table[tid].context as *const Context;
+        let pid = table[tid].pid;
+
+        drop(table);
+
+        (old_context, new_context, pid)
+    };
+
+    // 別のプロセスのスレッドならページテーブルを切り替え
+    // PROCESS_TABLE は THREAD_TABLE より先に取る決まりなので、ロックを外してから行う
+    uprocess::activate_address_space(pid);
+
+    // switched_out で reap したカーネルスタックを、ロックを外した状態で解放する
+    kthread::free_reaped();
+
+    unsafe {
+        switch_context(old_context, new_context);
+    }
+}
+
+/// 作成したスレッドをスケジューラに渡す
+/// スレッドを Runnable にして THREAD_TABLE のロックを外してから呼ぶ
+/// スケジューラを初期化する前に作ったスレッドは、スレッドテーブルを見るスケジューラだけが拾える
+pub fn ready(tid: usize) {
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.on_ready(tid);
+    }
+}
+
+/// スレッドの優先度を変える
+/// 0 が最も高く、NPRIORITY - 1 が最も低い
+pub fn set_priority(tid: usize, priority: usize) -> Result<(), &'static str> {
+    if priority >= NPRIORITY {
+        return Err("invalid priority");
+    }
+    {
+        let mut table = THREAD_TABLE.lock();
+        let thread = table.get_mut(tid).ok_or("no such thread")?;
+        if thread.state == ThreadState::Unused {
+            return Err("no such thread");
+        }
+        thread.priority = priority;
+    }
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.on_param_change(tid);
+    }
+    Ok(())
+}
+
+/// スレッドを実行できる CPU を変える
+/// mask のビット i が CPU ID i に対応し、登録済みの CPU を少なくとも 1 つ含まなければならない
+/// 実行中のスレッドは、スケジューラに戻ってきたときに移る
+pub fn set_affinity(tid: usize, mask: u64) -> Result<(), &'static str> {
+    if mask & cpu::all_cpus_mask() == 0 {
+        return Err("no CPU in affinity mask");
+    }
+    {
+        let mut table = THREAD_TABLE.lock();
+        let thread = table.get_mut(tid).ok_or("no such thread")?;
+        if thread.state == ThreadState::Unused {
+            return Err("no such thread");
+        }
+        thread.affinity = mask;
+    }
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.on_param_change(tid);
+    }
+    Ok(())
+}
+
+/// スレッドを実行できる CPU
+pub fn affinity(tid: usize) -> Result<u64, &'static str> {
+    let table = THREAD_TABLE.lock();
+    match table.get(tid) {
+        Some(thread) if thread.state != ThreadState::Unused => Ok(thread.affinity),
+        _ => Err("no such thread"),
+    }
+}
+
+/// スレッドの nice を変える
+/// MIN_NICE が最も多く、MAX_NICE が最も少なく CPU 時間を受け取る
+pub fn set_nice(tid: usize, nice: i8) -> Result<(), &'static str> {
+    if !(MIN_NICE..=MAX_NICE).contains(&nice) {
+        return Err("invalid nice");
+    }
+    {
+        let mut table = THREAD_TABLE.lock();
+        let thread = table.get_mut(tid).ok_or("no such thread")?;
+        if thread.state == ThreadState::Unused {
+            return Err("no such thread");
+        }
+        thread.nice = nice;
+    }
+    if let Some(scheduler) = SCHEDULER.get() {
+        scheduler.on_param_change(tid);
+    }
+    Ok(())
+}
+
+/// スレッドの nice
+pub fn nice(tid: usize) -> Result<i8, &'static str> {
+    let table = THREAD_TABLE.lock();
+    match table.get(tid) {
+        Some(thread) if thread.state != ThreadState::Unused => Ok(thread.nice),
+        _ => Err("no such thread"),
+    }
+}
+
+/// スレッドの優先度
+pub fn priority(tid: usize) -> Result<usize, &'static str> {
+    let table = THREAD_TABLE.lock();
+    match table.get(tid) {
+        Some(thread) if thread.state != ThreadState::Unused => Ok(thread.priority),
+        _ => Err("no such thread"),
+    }
+}
+
+#[test_case]
+fn test_set_affinity() {
+    // next_tid が割り当てない最後のスロットを借りる
+    let tid = NTHREAD - 1;
+    let mut thread = Thread::new();
+    thread.tid = tid;
+    thread.state = ThreadState::Embryo;
+    THREAD_TABLE.lock()[tid] = thread;
+
+    // 登録済みの CPU を 1 つも含まないマスクは断る
+    assert!(set_affinity(tid, 0).is_err());
+    assert_eq!(affinity(tid), Ok(thread.affinity));
+
+    assert_eq!(set_affinity(tid, 0b1), Ok(()));
+    assert_eq!(affinity(tid), Ok(0b1));
+    {
+        let table = THREAD_TABLE.lock();
+        assert!(table[tid].can_run_on(0));
+        assert!(!table[tid].can_run_on(1));
+    }
+
+    THREAD_TABLE.lock()[tid] = Thread::new();
+    assert!(set_affinity(tid, 0b1).is_err());
+}
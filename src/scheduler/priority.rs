@@ -0,0 +1,246 @@
+use core::sync::atomic::{ AtomicU64, AtomicUsize, Ordering };
+use super::{ Thread, ThreadState, THREAD_TABLE, NTHREAD, NPRIORITY, cpu };
+use super::run_queue::{ RunQueues, Visit };
+use crate::cpu::MAX_CPUS;
+use crate::libbackend::lock::IrqMutex;
+use x86_64::instructions::interrupts;
+
+/// どの CPU の列にも入っていない
+const NOT_QUEUED: usize = usize::MAX;
+
+/// 実行待ちの列に入れるときに Thread から写し取るパラメータ
+#[derive(Clone, Copy)]
+struct QueueParams {
+    priority: usize,
+    affinity: u64,
+    last_cpu: Option<usize>,
+}
+
+impl QueueParams {
+    /// Runnable なスレッドのパラメータ
+    fn of(thread: &Thread) -> Option<Self> {
+        (thread.state == ThreadState::Runnable).then_some(QueueParams {
+            priority: thread.priority,
+            affinity: thread.affinity,
+            last_cpu: thread.last_cpu,
+        })
+    }
+}
+
+/// 静的優先度スケジューラ
+/// 最も優先度の高い Runnable なスレッドを実行し、同じ優先度の中ではラウンドロビンで回す
+/// タイマ割り込みのたびにスケジューラに戻るので、高い優先度のスレッドが起きると次のティックまでに切り替わる
+///
+/// 実行待ちの列は CPU ごとに持ち、スレッドは affinity の許す CPU のうち、最後に実行した CPU か最も空いている CPU の列に入る
+/// 自分の列が空になった CPU は、最も混んでいる CPU の列から自分で実行できるスレッドを盗む
+/// 列の操作は THREAD_TABLE を持たずに行い、取り出したスレッドをまだ実行できるかは THREAD_TABLE を持って確かめる
+pub struct Priority {
+    run_queues: [IrqMutex<RunQueues<NPRIORITY>>; MAX_CPUS],
+    queued_on: [AtomicUsize; NTHREAD],      // スレッドが入っている列の CPU ID
+    affinity: [AtomicU64; NTHREAD],         // 列に入れたときの affinity
+}
+
+impl Priority {
+    pub fn new() -> Self {
+        Priority {
+            run_queues: core::array::from_fn(|_| IrqMutex::new(RunQueues::new())),
+            queued_on: [const { AtomicUsize::new(NOT_QUEUED) }; NTHREAD],
+            affinity: [const { AtomicU64::new(0) }; NTHREAD],
+        }
+    }
+
+    /// Runnable なスレッドを実行待ちの列に入れる
+    /// THREAD_TABLE を持たずに呼ぶ
+    fn enqueue(&self, tid: usize) {
+        let Some(params) = QueueParams::of(&THREAD_TABLE.lock()[tid]) else {
+            return;
+        };
+        self.push(tid, params, cpu::count());
+    }
+
+    /// count 個の CPU のうち params に合った CPU の列に tid を入れる
+    /// すでに別の CPU の列に入っていれば移す
+    /// run_queues のロックは一度に 1 つだけ取る
+    fn push(&self, tid: usize, params: QueueParams, count: usize) {
+        let cpu = self.select_cpu(params, count);
+        self.affinity[tid].store(params.affinity, Ordering::Relaxed);
+        let queued_on = self.queued_on[tid].swap(cpu, Ordering::Relaxed);
+        if queued_on != NOT_QUEUED && queued_on != cpu {
+            self.run_queues[queued_on].lock().remove(tid);
+        }
+        self.run_queues[cpu].lock().push(tid, params.priority);
+    }
+
+    /// count 個の CPU からスレッドを入れる CPU の列を選ぶ
+    /// 最後に実行した CPU で実行できればそこに、できなければ実行できる CPU のうち最も空いている CPU にする
+    fn select_cpu(&self, params: QueueParams, count: usize) -> usize {
+        let allowed = |cpu: usize| params.affinity & (1 << cpu) != 0;
+        if let Some(last_cpu) = params.last_cpu.filter(|&last_cpu| last_cpu < count && allowed(last_cpu)) {
+            return last_cpu;
+        }
+        (0..count)
+            .filter(|&cpu| allowed(cpu))
+            .min_by_key(|&cpu| self.run_queues[cpu].lock().len())
+            .unwrap_or(0)
+    }
+
+    /// count 個の CPU のうち CPU ID cpu で次に実行する候補を取り出す
+    /// 自分の列が空なら、混んでいる CPU の列から順に盗む
+    /// 列に入れてから状態が変わっていることがあるので、呼び出し側で THREAD_TABLE を見て確かめる
+    fn pick(&self, cpu: usize, count: usize) -> Option<usize> {
+        let allowed = |tid: usize| {
+            if self.affinity[tid].load(Ordering::Relaxed) & (1 << cpu) != 0 {
+                Visit::Take
+            } else {
+                Visit::Skip
+            }
+        };
+        let (tid, from) = self.run_queues[cpu].lock().pop_first(allowed).map(|tid| (tid, cpu)).or_else(|| {
+            let mut victims = [0; MAX_CPUS];
+            let mut loads = [0; MAX_CPUS];
+            for victim in 0..count {
+                victims[victim] = victim;
+                loads[victim] = self.run_queues[victim].lock().len();
+            }
+            victims[..count].sort_unstable_by_key(|&victim| core::cmp::Reverse(loads[victim]));
+            victims[..count].iter()
+                .filter(|&&victim| victim != cpu && loads[victim] > 0)
+                .find_map(|&victim| self.run_queues[victim].lock().pop_first(allowed).map(|tid| (tid, victim)))
+        })?;
+        // 取り出した後に別の列に入れ直されていれば、そちらを残す
+        let _ = self.queued_on[tid].compare_exchange(from, NOT_QUEUED, Ordering::Relaxed, Ordering::Relaxed);
+        Some(tid)
+    }
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl super::Scheduler for Priority {
+    /// スケジューラ
+    /// CPU ごとに呼び、自分の CPU の列から実行するスレッドを選ぶ
+    fn scheduler(&self) -> ! {
+        super::start_on_this_cpu();
+
+        // スケジューラを初期化する前に作ったスレッドを取り込む
+        for tid in 0..NTHREAD {
+            if self.queued_on[tid].load(Ordering::Relaxed) == NOT_QUEUED {
+                self.enqueue(tid);
+            }
+        }
+
+        loop {
+            interrupts::disable();
+
+            // スレッドからスケジューラに戻ってきた
+            // Runnable のまま戻ってきたら (プリエンプト・yield)、同じ優先度の列の末尾に戻す
+            if let Some(tid) = cpu::take_current_tid() {
+                super::switched_out(&mut THREAD_TABLE.lock()[tid]);
+                self.enqueue(tid);
+            }
+
+            let cpu = cpu::id();
+            let next = loop {
+                let Some(tid) = self.pick(cpu, cpu::count()) else {
+                    break None;
+                };
+                // Runnable でなくなったスレッドは捨てる
+                // まだ他の CPU でコンテキストを保存していないスレッドも、保存し終えたときに列に戻すので捨てる
+                // affinity が変わって cpu で実行できなくなったスレッドは、set_affinity が列に入れ直す
+                let table = THREAD_TABLE.lock();
+                if table[tid].state == ThreadState::Runnable && !table[tid].on_cpu && table[tid].can_run_on(cpu) {
+                    break Some((table, tid));
+                }
+            };
+            match next {
+                None => {
+                    // 割り込みが来るまで待つ
+                    interrupts::enable_and_hlt();
+                    continue;
+                }
+                Some((table, next_tid)) => super::run(table, next_tid),
+            }
+        }
+    }
+
+    /// chan で Sleeping になっているスレッドを Runnable にして、実行待ちの列に入れる
+    fn on_wakeup(&self, chan: usize) {
+        let mut woken = [false; NTHREAD];
+        {
+            let mut table = THREAD_TABLE.lock();
+            for tid in 0..NTHREAD {
+                if table[tid].state == ThreadState::Sleeping && table[tid].chan == Some(chan) {
+                    table[tid].state = ThreadState::Runnable;
+                    table[tid].chan = None;
+                    woken[tid] = true;
+                }
+            }
+        }
+        for tid in (0..NTHREAD).filter(|&tid| woken[tid]) {
+            self.enqueue(tid);
+        }
+    }
+
+    fn on_ready(&self, tid: usize) {
+        self.enqueue(tid);
+    }
+
+    /// 実行待ちの列に入っていれば、新しい優先度・affinity の列に移す
+    fn on_param_change(&self, tid: usize) {
+        self.enqueue(tid);
+    }
+}
+
+#[test_case]
+fn test_priority_select_cpu() {
+    let priority = Priority::new();
+    let params = QueueParams { priority: 0, affinity: u64::MAX, last_cpu: None };
+    priority.run_queues[0].lock().push(1, 0);
+    priority.run_queues[0].lock().push(2, 0);
+    priority.run_queues[2].lock().push(3, 0);
+
+    // 最も空いている CPU
+    assert_eq!(priority.select_cpu(params, 3), 1);
+    // 最後に実行した CPU で実行できればそこ
+    assert_eq!(priority.select_cpu(QueueParams { last_cpu: Some(2), ..params }, 3), 2);
+    // 最後に実行した CPU がいなくなった・affinity で外れたときは、実行できる中で最も空いている CPU
+    assert_eq!(priority.select_cpu(QueueParams { last_cpu: Some(5), ..params }, 3), 1);
+    assert_eq!(priority.select_cpu(QueueParams { affinity: 0b101, last_cpu: Some(1), ..params }, 3), 2);
+    assert_eq!(priority.select_cpu(params, 1), 0);
+}
+
+#[test_case]
+fn test_priority_push_moves_between_cpus() {
+    let priority = Priority::new();
+    let params = QueueParams { priority: 3, affinity: u64::MAX, last_cpu: None };
+    priority.push(1, params, 2);
+    priority.push(2, params, 2);
+    assert_eq!(priority.queued_on[1].load(Ordering::Relaxed), 0);
+    assert_eq!(priority.queued_on[2].load(Ordering::Relaxed), 1);
+
+    // affinity が変わったら、実行できる CPU の列に移す
+    priority.push(1, QueueParams { affinity: 0b10, ..params }, 2);
+    assert_eq!(priority.run_queues[0].lock().len(), 0);
+    assert_eq!(priority.run_queues[1].lock().len(), 2);
+    assert_eq!(priority.queued_on[1].load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn test_priority_steals_from_busiest() {
+    let priority = Priority::new();
+    let params = QueueParams { priority: 3, affinity: u64::MAX, last_cpu: None };
+    priority.push(5, QueueParams { affinity: 0b100, last_cpu: Some(2), ..params }, 3);
+    priority.push(6, QueueParams { last_cpu: Some(2), ..params }, 3);
+    priority.push(7, QueueParams { last_cpu: Some(1), ..params }, 3);
+
+    // 最も混んでいる CPU 2 から、CPU 0 で実行できるスレッドを盗む
+    assert_eq!(priority.pick(0, 3), Some(6));
+    assert_eq!(priority.queued_on[6].load(Ordering::Relaxed), NOT_QUEUED);
+    assert_eq!(priority.pick(0, 3), Some(7));
+    // CPU 0 で実行できないスレッドは残す
+    assert_eq!(priority.pick(0, 3), None);
+    assert_eq!(priority.pick(2, 3), Some(5));
+}
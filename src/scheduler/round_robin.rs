@@ -1,108 +1,67 @@
-use super::{ Thread, ThreadState, THREAD_TABLE, NTHREAD, cpu::CPU, SCHEDULER_STARTED };
-use super::context::{ Context, switch_context };
+use super::{ Thread, ThreadState, THREAD_TABLE, NTHREAD, cpu };
+use x86_64::instructions::interrupts;
 
 pub struct RoundRobin;
 
 impl super::Scheduler for RoundRobin {
     /// スケジューラ
+    /// CPU ごとに呼び、すべての CPU が THREAD_TABLE を共有して実行するスレッドを選ぶ
+    /// スケジューラ自身は割り込みを禁止して動き、スレッドの rflags で割り込みの状態が戻る
     fn scheduler(&self) -> ! {
-        unsafe {
-            if SCHEDULER_STARTED {
-                panic!("Scheduler already started");
-            }
-            SCHEDULER_STARTED = true;
-        }
+        super::start_on_this_cpu();
+
+        // 最後に実行したスレッド (次はこの次から探す)
+        let mut last_tid = None;
 
         loop {
+            interrupts::disable();
+
             let mut table = THREAD_TABLE.lock();
-            let mut cpu = CPU.lock();
-            
+
+            // スレッドからスケジューラに戻ってきた
+            if let Some(tid) = cpu::take_current_tid() {
+                super::switched_out(&mut table[tid]);
+                last_tid = Some(tid);
+            }
+
             // 次に実行するスレッドの決定
             let next_tid = {
-                find_next_runnable_thread(&table, cpu.current_tid)
+                find_next_runnable_thread(&table, last_tid)
             };
 
             match next_tid {
                 None => {
-                    x86_64::instructions::interrupts::enable_and_hlt();
-                    drop(cpu);
+                    // ロックを外してから、割り込みが来るまで待つ
                     drop(table);
+                    interrupts::enable_and_hlt();
                     continue;
                 }
-                Some(next_tid) => {
-                    let (old_context, new_context) = {
-                        // スレッド状態を更新
-                        table[next_tid].state = ThreadState::Running;
-                        if let Some(current_tid) = cpu.current_tid {
-                            if table[current_tid].state == ThreadState::Running {
-                                table[current_tid].state = ThreadState::Runnable;
-                            }
-                        }
-                        
-                        // CPU で実行中のスレッド ID を更新
-                        cpu.current_tid = Some(next_tid);
-                        
-                        let old_context = &mut cpu.scheduler as *mut Context;
-                        let new_context = &table[next_tid].context as *const Context;
-
-                        drop(cpu);
-                        drop(table);
-
-                        (old_context, new_context)
-                    };
-
-                    unsafe {
-                        x86_64::instructions::interrupts::enable();
-                        //crate::println!("switch");
-                        switch_context(old_context, new_context);
-                    }
-                }
+                Some(next_tid) => super::run(table, next_tid),
             }
         }
     }
 
-    /// スレッドからスケジューラに戻る
-    fn on_yield(&self) {
-        x86_64::instructions::interrupts::disable();
-
+    /// chan で Sleeping になっているスレッドを Runnable にする
+    fn on_wakeup(&self, chan: usize) {
         let mut table = THREAD_TABLE.lock();
-        let cpu = CPU.lock();
-
-        let current_tid = cpu.current_tid;
-        if current_tid.is_none() {
-            x86_64::instructions::interrupts::enable();
-            return;
-        }
-        let current_tid = current_tid.unwrap();
-        if table[current_tid].state != ThreadState::Running {
-            panic!("CPU has current_tid but the thread is not Running");
-        }
-
-        let (old_context, new_context) = {
-            // Runnable に変更
-            table[current_tid].state = ThreadState::Runnable;
-
-            // スケジューラへコンテキストスイッチ
-            let old_context = &mut table[current_tid].context as *mut Context;
-            let new_context = &cpu.scheduler as *const Context;
-
-            drop(cpu);
-            drop(table);
-
-            (old_context, new_context)
-        };
-        unsafe {
-            x86_64::instructions::interrupts::enable();
-            switch_context(old_context, new_context);
+        for thread in table.iter_mut() {
+            if thread.state == ThreadState::Sleeping && thread.chan == Some(chan) {
+                thread.state = ThreadState::Runnable;
+                thread.chan = None;
+            }
         }
     }
 }
 
+/// current_tid の次から、この CPU で実行できる Runnable なスレッドを探す
+/// Sleeping のスレッドは wakeup されるまで選ばない
+/// 他の CPU がまだコンテキストを保存していないスレッド (on_cpu) も選ばない
 fn find_next_runnable_thread(table: &[Thread; NTHREAD], current_tid: Option<usize>) -> Option<usize> {
     let current_tid = current_tid.unwrap_or(0);
+    let cpu = cpu::id();
     for i in 1..NTHREAD+1 {
         let tid = (current_tid + i) % NTHREAD;
-        if table[tid].state == ThreadState::Runnable {
+        if table[tid].state == ThreadState::Runnable && !table[tid].on_cpu && table[tid].can_run_on(cpu) {
             return Some(tid);
         }
     }
@@ -0,0 +1,191 @@
+use super::{ Thread, ThreadState, NTHREAD };
+
+/// pop_first で列の先頭から見ていくスレッドの扱い
+pub(super) enum Visit {
+    Take,   // 取り出して返す
+    Skip,   // 列に残して次を見る
+    Drop,   // 列から捨てて次を見る
+}
+
+/// 列の中の Thread ID・段階
+/// Priority は CPU ごとに列を持つので、小さく収まるよう u8 で持つ
+type Link = Option<u8>;
+
+const _: () = assert!(NTHREAD <= u8::MAX as usize);
+
+/// 段階ごとの実行待ちの列
+/// 段階 0 の列から順に取り出す
+/// 割り込みハンドラの wakeup からも入れるので、ヒープを使わず、スレッドごとの前後のリンクで列をつなぐ
+pub(super) struct RunQueues<const N: usize> {
+    heads: [Link; N],
+    tails: [Link; N],
+    next: [Link; NTHREAD],
+    prev: [Link; NTHREAD],
+    queued: [Link; NTHREAD],    // スレッドが入っている列の段階 (1 つのスレッドは 1 つの列に 1 度だけ入る)
+    len: usize,
+}
+
+impl<const N: usize> RunQueues<N> {
+    pub(super) fn new() -> Self {
+        RunQueues {
+            heads: [None; N],
+            tails: [None; N],
+            next: [None; NTHREAD],
+            prev: [None; NTHREAD],
+            queued: [None; NTHREAD],
+            len: 0,
+        }
+    }
+
+    /// tid を level の列の末尾に入れる
+    /// すでに別の段階の列に入っていれば移す
+    pub(super) fn push(&mut self, tid: usize, level: usize) {
+        match self.queued[tid].map(usize::from) {
+            Some(queued) if queued == level => return,
+            Some(_) => self.remove(tid),
+            None => {}
+        }
+        self.link_back(tid, level);
+    }
+
+    /// 最も小さい段階の列の先頭から順に visit に渡し、Take を返したスレッドを取り出す
+    pub(super) fn pop_first(&mut self, mut visit: impl FnMut(usize) -> Visit) -> Option<usize> {
+        for level in 0..N {
+            let mut cursor = self.heads[level].map(usize::from);
+            while let Some(tid) = cursor {
+                cursor = self.next[tid].map(usize::from);
+                match visit(tid) {
+                    Visit::Take => {
+                        self.remove(tid);
+                        return Some(tid);
+                    }
+                    Visit::Drop => self.remove(tid),
+                    Visit::Skip => {}
+                }
+            }
+        }
+        None
+    }
+
+    /// 最も小さい段階の列の先頭から、CPU ID cpu で実行できるスレッドを取り出す
+    /// affinity で cpu では実行できないスレッドは列に残す
+    /// Runnable でなくなったスレッドは捨てる
+    /// まだ他の CPU でコンテキストを保存していないスレッドも、保存し終えたときに列に戻すので捨てる
+    pub(super) fn pop_runnable(&mut self, table: &[Thread; NTHREAD], cpu: usize) -> Option<usize> {
+        self.pop_first(|tid| {
+            let thread = &table[tid];
+            if thread.state != ThreadState::Runnable || thread.on_cpu {
+                Visit::Drop
+            } else if thread.can_run_on(cpu) {
+                Visit::Take
+            } else {
+                Visit::Skip
+            }
+        })
+    }
+
+    /// tid が列に入っていれば取り除く
+    pub(super) fn remove(&mut self, tid: usize) {
+        let Some(level) = self.queued[tid].take().map(usize::from) else {
+            return;
+        };
+        let (prev, next) = (self.prev[tid].take(), self.next[tid].take());
+        match prev {
+            Some(prev) => self.next[prev as usize] = next,
+            None => self.heads[level] = next,
+        }
+        match next {
+            Some(next) => self.prev[next as usize] = prev,
+            None => self.tails[level] = prev,
+        }
+        self.len -= 1;
+    }
+
+    /// 列に入っているスレッドの数
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// スレッドが入っている最も小さい段階
+    pub(super) fn highest_level(&self) -> Option<usize> {
+        self.heads.iter().position(|head| head.is_some())
+    }
+
+    /// すべての列のスレッドを、段階の順・列の中の順を保ったまま level の列に移す
+    pub(super) fn move_all_to(&mut self, level: usize) {
+        for from in (0..N).filter(|&from| from != level) {
+            while let Some(tid) = self.heads[from].map(usize::from) {
+                self.remove(tid);
+                self.link_back(tid, level);
+            }
+        }
+    }
+
+    /// 列に入っていない tid を level の列の末尾につなぐ
+    fn link_back(&mut self, tid: usize, level: usize) {
+        let link = Some(tid as u8);
+        self.prev[tid] = self.tails[level];
+        self.next[tid] = None;
+        match self.tails[level] {
+            Some(tail) => self.next[tail as usize] = link,
+            None => self.heads[level] = link,
+        }
+        self.tails[level] = link;
+        self.queued[tid] = Some(level as u8);
+        self.len += 1;
+    }
+}
+
+#[test_case]
+fn test_run_queues_order() {
+    let mut queues = RunQueues::<3>::new();
+    queues.push(3, 1);
+    queues.push(1, 0);
+    queues.push(2, 1);
+    queues.push(5, 0);
+    queues.push(4, 2);
+    // 同じ段階に入れ直しても順は変わらない
+    queues.push(3, 1);
+    assert_eq!(queues.len(), 5);
+
+    // 列に残したスレッドは次にも見る、捨てたスレッドはもう出てこない
+    assert_eq!(queues.pop_first(|tid| if tid == 1 { Visit::Skip } else { Visit::Drop }), None);
+    assert_eq!(queues.len(), 1);
+    queues.push(5, 0);
+    queues.push(3, 1);
+    queues.push(2, 1);
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(1));
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(5));
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(3));
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(2));
+    assert_eq!(queues.pop_first(|_| Visit::Take), None);
+    assert_eq!(queues.len(), 0);
+}
+
+#[test_case]
+fn test_run_queues_reprioritize() {
+    let mut queues = RunQueues::<3>::new();
+    queues.push(1, 2);
+    queues.push(2, 2);
+    queues.push(3, 2);
+    queues.push(4, 1);
+
+    // 別の段階に入れ直すと、その段階の列の末尾に移る
+    queues.push(2, 0);
+    queues.push(4, 2);
+    queues.remove(3);
+    queues.remove(3);
+    assert_eq!(queues.len(), 3);
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(2));
+
+    queues.push(2, 1);
+    queues.push(3, 0);
+    // 段階の順、列の中の順を保ったまま移す
+    queues.move_all_to(2);
+    assert_eq!(queues.len(), 4);
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(1));
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(4));
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(3));
+    assert_eq!(queues.pop_first(|_| Visit::Take), Some(2));
+    assert_eq!(queues.pop_first(|_| Visit::Take), None);
+}
@@ -0,0 +1,144 @@
+use super::NTHREAD;
+
+/// キー (vruntime・絶対デッドラインなど) の小さい順にスレッドを並べるヒープ
+/// Thread ID から位置を引けるので、列の途中のスレッドも取り除ける
+/// 割り込みハンドラの wakeup からも入れるので、固定長の配列で持つ
+pub(super) struct Timeline {
+    heap: [usize; NTHREAD],             // Thread ID
+    len: usize,
+    pos: [Option<usize>; NTHREAD],      // スレッドの heap での位置
+    key: [u64; NTHREAD],                // 入れたときのキー
+}
+
+impl Timeline {
+    pub(super) const fn new() -> Self {
+        Timeline {
+            heap: [0; NTHREAD],
+            len: 0,
+            pos: [None; NTHREAD],
+            key: [0; NTHREAD],
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(super) fn contains(&self, tid: usize) -> bool {
+        self.pos[tid].is_some()
+    }
+
+    /// tid を key の位置に入れる
+    /// すでに入っていれば、新しい key の位置に移す
+    pub(super) fn insert(&mut self, tid: usize, key: u64) {
+        self.remove(tid);
+        self.key[tid] = key;
+        self.heap[self.len] = tid;
+        self.pos[tid] = Some(self.len);
+        self.len += 1;
+        self.sift_up(self.len - 1);
+    }
+
+    /// tid を取り除く
+    pub(super) fn remove(&mut self, tid: usize) -> bool {
+        let Some(i) = self.pos[tid] else {
+            return false;
+        };
+        self.len -= 1;
+        self.pos[tid] = None;
+        if i != self.len {
+            self.heap[i] = self.heap[self.len];
+            self.pos[self.heap[i]] = Some(i);
+            self.sift_down(i);
+            self.sift_up(i);
+        }
+        true
+    }
+
+    /// キーの最も小さいスレッドを取り出す
+    #[cfg(test)]
+    pub(super) fn pop_min(&mut self) -> Option<usize> {
+        let tid = *self.heap[..self.len].first()?;
+        self.remove(tid);
+        Some(tid)
+    }
+
+    /// f を満たすスレッドのうち、キーの最も小さいものを取り出す
+    /// 満たさないスレッドは列に残す
+    /// ヒープの配列をすべて見て、見つけたスレッドだけを取り除く
+    pub(super) fn pop_min_where(&mut self, mut f: impl FnMut(usize) -> bool) -> Option<usize> {
+        let tid = self.heap[..self.len].iter()
+            .copied()
+            .filter(|&tid| f(tid))
+            .min_by_key(|&tid| (self.key[tid], tid))?;
+        self.remove(tid);
+        Some(tid)
+    }
+
+    /// キーの最も小さいスレッドとそのキー
+    pub(super) fn peek_min(&self) -> Option<(usize, u64)> {
+        let tid = *self.heap[..self.len].first()?;
+        Some((tid, self.key[tid]))
+    }
+
+    /// (キー, Thread ID) の順に比べる
+    fn less(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (self.heap[a], self.heap[b]);
+        (self.key[a], a) < (self.key[b], b)
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.pos[self.heap[a]] = Some(a);
+        self.pos[self.heap[b]] = Some(b);
+    }
+
+    fn sift_up(&mut self, mut i: usize) {
+        while i > 0 {
+            let parent = (i - 1) / 2;
+            if !self.less(i, parent) {
+                break;
+            }
+            self.swap(i, parent);
+            i = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut i: usize) {
+        loop {
+            let (left, right) = (2 * i + 1, 2 * i + 2);
+            let mut smallest = i;
+            if left < self.len && self.less(left, smallest) {
+                smallest = left;
+            }
+            if right < self.len && self.less(right, smallest) {
+                smallest = right;
+            }
+            if smallest == i {
+                break;
+            }
+            self.swap(i, smallest);
+            i = smallest;
+        }
+    }
+}
+
+#[test_case]
+fn test_timeline_order() {
+    let mut timeline = Timeline::new();
+    timeline.insert(3, 300);
+    timeline.insert(1, 100);
+    timeline.insert(2, 200);
+    timeline.insert(4, 50);
+    // 入れ直すと位置が変わる
+    timeline.insert(4, 250);
+    assert_eq!(timeline.peek_min(), Some((1, 100)));
+    assert_eq!(timeline.pop_min_where(|tid| tid % 2 == 0), Some(2));
+    timeline.insert(2, 200);
+    assert!(timeline.remove(2));
+    assert!(!timeline.remove(2));
+    assert_eq!(timeline.pop_min(), Some(1));
+    assert_eq!(timeline.pop_min(), Some(4));
+    assert_eq!(timeline.pop_min(), Some(3));
+    assert_eq!(timeline.pop_min(), None);
+}
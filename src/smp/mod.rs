@@ -0,0 +1,130 @@
+use core::sync::atomic::{ AtomicBool, Ordering };
+use conquer_once::spin::OnceCell;
+use x86_64::registers::control::{ Cr0, Cr0Flags, Cr4, Cr4Flags };
+use x86_64::registers::model_specific::{ Efer, EferFlags };
+use crate::acpi::madt::Madt;
+use crate::interrupts::{ self, lapic };
+use crate::time::pit;
+use crate::{ cpu, fpu, gdt, memory, println, scheduler, syscall, thread };
+
+mod trampoline;
+
+/// INIT IPI の後に待つ時間 (約 10ms)
+const INIT_DELAY_PIT_COUNT: u16 = 11932;
+/// Startup IPI の後に待つ時間 (約 200us)
+const STARTUP_DELAY_PIT_COUNT: u16 = 239;
+/// AP が起動したことを知らせるまで待つ回数 (約 1ms ずつ)
+const STARTUP_TIMEOUT_MS: usize = 100;
+
+/// 起動中の AP が ap_main まで来たか
+static AP_STARTED: AtomicBool = AtomicBool::new(false);
+
+/// BSP の制御レジスタ
+/// AP も同じ設定にする
+static BSP_CONTROL: OnceCell<(Cr0Flags, Cr4Flags, EferFlags)> = OnceCell::uninit();
+
+/// MADT に載っている AP を INIT-SIPI-SIPI で起動する
+/// AP はそれぞれ自分の GDT・TSS・IDT をロードし、スケジューラを実行する
+/// APIC を初期化した後、BSP がスケジューラを実行する前に呼ぶ
+/// 起動した AP の数を返す
+pub fn init() -> Result<usize, &'static str> {
+    if !interrupts::apic_enabled() {
+        return Err("APIC not enabled");
+    }
+    let madt = Madt::get().ok_or("MADT not found")?;
+
+    let bsp = lapic::id();
+    cpu::register(bsp).ok_or("too many CPUs")?;
+    BSP_CONTROL.init_once(|| (Cr0::read(), Cr4::read(), Efer::read()));
+
+    // 起動コードを 1 MiB 未満のフレームに置く
+    // ページングを有効にした直後も同じアドレスで実行を続けられるよう、同じ仮想アドレスにもマップする
+    let frame = memory::find_low_frame().ok_or("no free frame below 1 MiB")?;
+    memory::identity_map(frame)?;
+    let base = frame.start_address();
+    let code = trampoline::code();
+    unsafe {
+        let dest = memory::phys_to_virt(base).as_mut_ptr::<u8>();
+        core::ptr::copy_nonoverlapping(code.as_ptr(), dest, code.len());
+    }
+
+    // AP はリアルモードから CR3 を 32 ビットで設定する
+    let cr3 = u32::try_from(memory::kernel_pml4().start_address().as_u64())
+        .map_err(|_| "kernel page table above 4 GiB")?;
+
+    let mut started = 0;
+    for apic_id in madt.apic_ids().filter(|&apic_id| apic_id != bsp) {
+        let Some(cpu_id) = cpu::register(apic_id) else {
+            println!("\tCPU limit ({}) reached, ignoring APIC {}", cpu::MAX_CPUS, apic_id);
+            break;
+        };
+
+        let Ok(stack_top) = thread::alloc_kstack() else {
+            println!("\tNo memory for the kernel stack of APIC {}", apic_id);
+            cpu::unregister(cpu_id);
+            break;
+        };
+        let args = trampoline::TrampolineArgs {
+            cr3,
+            stack_top,
+            entry: ap_main,
+            cpu_id,
+        };
+        trampoline::write_args(base, &args);
+
+        AP_STARTED.store(false, Ordering::Release);
+        if start_ap(apic_id, (base.as_u64() >> 12) as u8) {
+            started += 1;
+        } else {
+            println!("\tAPIC {} did not start", apic_id);
+            thread::free_kstack(args.stack_top);
+            cpu::unregister(cpu_id);
+        }
+    }
+    Ok(started)
+}
+
+/// INIT-SIPI-SIPI を送り、AP が ap_main に入るまで待つ
+fn start_ap(apic_id: u8, page: u8) -> bool {
+    lapic::send_init(apic_id);
+    pit::busy_wait(INIT_DELAY_PIT_COUNT);
+
+    for _ in 0..2 {
+        lapic::send_startup(apic_id, page);
+        pit::busy_wait(STARTUP_DELAY_PIT_COUNT);
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+    }
+
+    for _ in 0..STARTUP_TIMEOUT_MS {
+        if AP_STARTED.load(Ordering::Acquire) {
+            return true;
+        }
+        pit::busy_wait(INIT_DELAY_PIT_COUNT / 10);
+    }
+    AP_STARTED.load(Ordering::Acquire)
+}
+
+/// AP のエントリポイント
+/// 起動コードから割り込み禁止の状態で呼ばれる
+extern "C" fn ap_main(cpu_id: usize) -> ! {
+    // BSP と同じ制御レジスタの設定にする
+    let (cr0, cr4, efer) = *BSP_CONTROL.get().expect("AP started before smp::init");
+    unsafe {
+        Cr0::write(cr0);
+        Cr4::write(cr4);
+        Efer::write(efer);
+    }
+
+    cpu::init(cpu_id);
+    fpu::init();
+    gdt::init(cpu_id);
+    interrupts::init_idt();
+    syscall::init();
+    interrupts::init_ap();
+
+    AP_STARTED.store(true, Ordering::Release);
+
+    scheduler::scheduler();
+}
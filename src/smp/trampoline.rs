@@ -0,0 +1,150 @@
+use core::arch::global_asm;
+use x86_64::PhysAddr;
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_cr3: u8;
+    static ap_stack: u8;
+    static ap_entry: u8;
+    static ap_cpu_id: u8;
+}
+
+/// AP の起動コードに渡す値
+pub struct TrampolineArgs {
+    /// level4 テーブルの物理アドレス (4 GiB 未満)
+    pub cr3: u32,
+    /// AP が使うスタックの上端
+    pub stack_top: u64,
+    /// ロングモードに入った後に呼ぶ関数
+    pub entry: extern "C" fn(usize) -> !,
+    pub cpu_id: usize,
+}
+
+/// 起動コードのバイト列
+pub fn code() -> &'static [u8] {
+    unsafe {
+        let start = &raw const ap_trampoline_start;
+        let end = &raw const ap_trampoline_end;
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+/// 物理アドレス base にコピーした起動コードに、args を書き込む
+pub fn write_args(base: PhysAddr, args: &TrampolineArgs) {
+    let start = &raw const ap_trampoline_start as u64;
+    let virt = |symbol: *const u8| crate::memory::phys_to_virt(base + (symbol as u64 - start));
+    unsafe {
+        virt(&raw const ap_cr3).as_mut_ptr::<u64>().write_volatile(args.cr3 as u64);
+        virt(&raw const ap_stack).as_mut_ptr::<u64>().write_volatile(args.stack_top);
+        virt(&raw const ap_entry).as_mut_ptr::<u64>().write_volatile(args.entry as usize as u64);
+        virt(&raw const ap_cpu_id).as_mut_ptr::<u64>().write_volatile(args.cpu_id as u64);
+    }
+}
+
+// AP の起動コード
+// Startup IPI を受けた AP は、CS = ページ番号 << 8, IP = 0 のリアルモードでここから実行を始める
+// 4 KiB 未満の位置独立なコードで、1 MiB 未満のフレームにコピーして使う
+// リアルモードから直接ロングモードに入り、ap_entry(ap_cpu_id) を呼ぶ
+global_asm!(
+r#"
+.pushsection .rodata.ap_trampoline, "a"
+.globl ap_trampoline_start
+.globl ap_trampoline_end
+.globl ap_cr3
+.globl ap_stack
+.globl ap_entry
+.globl ap_cpu_id
+
+.code16
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+
+    # ebx = 起動コードの物理アドレス
+    xor %ebx, %ebx
+    mov %cs, %bx
+    shl $4, %ebx
+
+    # GDTR とロングモードへの far jump 先を物理アドレスで埋める
+    lea (ap_gdt - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_gdtr - ap_trampoline_start + 2)
+    lea (ap_long_mode - ap_trampoline_start)(%ebx), %eax
+    mov %eax, (ap_far_ptr - ap_trampoline_start)
+    lgdtl (ap_gdtr - ap_trampoline_start)
+
+    # PAE
+    mov %cr4, %eax
+    or $(1 << 5), %eax
+    mov %eax, %cr4
+
+    # カーネルの level4 テーブル
+    mov (ap_cr3 - ap_trampoline_start), %eax
+    mov %eax, %cr3
+
+    # EFER.LME, EFER.NXE
+    mov $0xC0000080, %ecx
+    rdmsr
+    or $((1 << 8) | (1 << 11)), %eax
+    wrmsr
+
+    # プロテクトモードとページングを同時に有効にする
+    mov %cr0, %eax
+    or $((1 << 31) | (1 << 0)), %eax
+    mov %eax, %cr0
+
+    # 64 ビットのコードセグメントへ
+    ljmpl *(ap_far_ptr - ap_trampoline_start)
+
+.code64
+ap_long_mode:
+    mov $0x10, %ax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    xor %ax, %ax
+    mov %ax, %fs
+    mov %ax, %gs
+
+    mov ap_stack(%rip), %rsp
+    mov ap_cpu_id(%rip), %rdi
+    xor %ebp, %ebp
+    call *ap_entry(%rip)
+1:
+    hlt
+    jmp 1b
+
+.balign 16
+ap_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff    # 0x08: 64 ビットコード
+    .quad 0x00cf92000000ffff    # 0x10: データ
+ap_gdtr:
+    .word 3 * 8 - 1
+    .long 0
+.balign 8
+ap_far_ptr:
+    .long 0
+    .word 0x08
+.balign 8
+ap_cr3:
+    .quad 0
+ap_stack:
+    .quad 0
+ap_entry:
+    .quad 0
+ap_cpu_id:
+    .quad 0
+ap_trampoline_end:
+.popsection
+"#,
+    options(att_syntax)
+);
+
+#[test_case]
+fn test_trampoline_fits_in_a_page() {
+    assert!(!code().is_empty());
+    assert!(code().len() <= 4096);
+}
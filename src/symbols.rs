@@ -0,0 +1,105 @@
+use core::hint::black_box;
+
+/// シンボルテーブル用に確保する領域の大きさ
+const KSYMTAB_SIZE: usize = 1024 * 1024;
+
+/// シンボルテーブル
+/// ビルド後に tools/ksymtab.py が中身を書き込む（形式はスクリプトを参照）
+/// 書き込まれていなければ 0 のまま
+#[used]
+#[unsafe(link_section = ".ksymtab")]
+static KSYMTAB: [u8; KSYMTAB_SIZE] = [0; KSYMTAB_SIZE];
+
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 16;
+const ENTRY_SIZE: usize = 24;
+
+/// シンボルテーブルのエントリ
+struct Symbol {
+    addr: u64,
+    size: u64,
+    name: &'static str,
+}
+
+/// シンボルテーブル
+struct SymbolTable {
+    data: &'static [u8],
+    count: usize,
+    strtab: &'static [u8],
+}
+
+impl SymbolTable {
+    /// 埋め込まれたシンボルテーブルを読む
+    /// 書き込まれていなければ None
+    fn get() -> Option<Self> {
+        // 中身はビルド後に書き換えるので、コンパイル時の値 (すべて 0) で畳み込まれないようにする
+        let data: &'static [u8] = unsafe { &*black_box(&raw const KSYMTAB) };
+        if &data[0..4] != MAGIC {
+            return None;
+        }
+        let count = read_u32(data, 4) as usize;
+        let strtab_offset = read_u32(data, 8) as usize;
+        let strtab_size = read_u32(data, 12) as usize;
+        if HEADER_SIZE + count * ENTRY_SIZE > strtab_offset {
+            return None;
+        }
+        let strtab = data.get(strtab_offset..strtab_offset + strtab_size)?;
+        Some(SymbolTable { data, count, strtab })
+    }
+
+    fn symbol(&self, index: usize) -> Symbol {
+        let offset = HEADER_SIZE + index * ENTRY_SIZE;
+        let name_offset = read_u32(self.data, offset + 16) as usize;
+        let name_len = read_u32(self.data, offset + 20) as usize;
+        let name = self.strtab.get(name_offset..name_offset + name_len)
+            .and_then(|name| core::str::from_utf8(name).ok())
+            .unwrap_or("?");
+        Symbol {
+            addr: read_u64(self.data, offset),
+            size: read_u64(self.data, offset + 8),
+            name,
+        }
+    }
+
+    /// addr 以下で最大のアドレスを持つシンボルを探す
+    fn lookup(&self, addr: u64) -> Option<Symbol> {
+        // addr より大きいアドレスを持つ最初のエントリを二分探索する
+        let (mut low, mut high) = (0, self.count);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.symbol(mid).addr <= addr {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        if low == 0 {
+            return None;
+        }
+        Some(self.symbol(low - 1))
+    }
+}
+
+/// アドレスを含む関数の名前と、関数先頭からのオフセットを返す
+pub fn resolve(addr: u64) -> Option<(&'static str, u64)> {
+    let symbol = SymbolTable::get()?.lookup(addr)?;
+    let offset = addr - symbol.addr;
+    // サイズ不明のシンボルは次のシンボルまで続いているとみなす
+    if symbol.size != 0 && offset >= symbol.size {
+        return None;
+    }
+    Some((symbol.name, offset))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+#[test_case]
+fn test_resolve_outside_kernel() {
+    assert_eq!(resolve(0), None);
+}
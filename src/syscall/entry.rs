@@ -0,0 +1,122 @@
+use core::arch::global_asm;
+use crate::cpu;
+
+/// syscall 時に保存するユーザのレジスタ
+/// メンバの並びはエントリスタブで push する順序の逆順
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,        // 第6引数
+    pub r8: u64,        // 第5引数
+    pub r10: u64,       // 第4引数
+    pub rdx: u64,       // 第3引数
+    pub rsi: u64,       // 第2引数
+    pub rdi: u64,       // 第1引数
+    pub rax: u64,       // システムコール番号 / 戻り値
+    pub rflags: u64,    // syscall が r11 に退避した RFLAGS
+    pub rip: u64,       // syscall が rcx に退避した戻り先
+    pub rsp: u64,       // ユーザスタック
+}
+
+impl SyscallFrame {
+    /// rip から rsp をスタックとして実行を始めるフレーム
+    /// その他のレジスタは 0 にする
+    pub fn new(rip: u64, rsp: u64) -> Self {
+        SyscallFrame {
+            r15: 0,
+            r14: 0,
+            r13: 0,
+            r12: 0,
+            rbp: 0,
+            rbx: 0,
+            r9: 0,
+            r8: 0,
+            r10: 0,
+            rdx: 0,
+            rsi: 0,
+            rdi: 0,
+            rax: 0,
+            rflags: 0x202,  // IF (Interrupt Flag) を有効化
+            rip,
+            rsp,
+        }
+    }
+}
+
+unsafe extern "C" {
+    pub fn syscall_entry();
+
+    /// SyscallFrame を指す rsp からユーザモードに戻る
+    pub fn syscall_return();
+}
+
+// syscall エントリポイント
+// swapgs で GS ベースをこの CPU の PerCpu に切り替え、実行中スレッドのカーネルスタックへ切り替える
+// SyscallFrame を積んで振り分ける
+global_asm!(
+r#"
+.globl syscall_entry
+syscall_entry:
+    # GS ベースをこの CPU の PerCpu に切り替え
+    swapgs
+
+    # ユーザスタックを退避し、カーネルスタックへ切り替え
+    mov gs:[{user_rsp}], rsp
+    mov rsp, gs:[{kernel_rsp}]
+
+    # SyscallFrame を作成
+    push qword ptr gs:[{user_rsp}]
+    push rcx
+    push r11
+    push rax
+    push rdi
+    push rsi
+    push rdx
+    push r10
+    push r8
+    push r9
+    push rbx
+    push rbp
+    push r12
+    push r13
+    push r14
+    push r15
+
+    mov rdi, rsp
+    call {dispatch}
+
+.globl syscall_return
+syscall_return:
+    # rsp は SyscallFrame を指している
+    cli
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop rbp
+    pop rbx
+    pop r9
+    pop r8
+    pop r10
+    pop rdx
+    pop rsi
+    pop rdi
+    pop rax
+    pop r11
+    pop rcx
+    pop rsp
+
+    # GS ベースをユーザモードのものに戻す
+    swapgs
+    sysretq
+"#,
+    user_rsp = const cpu::USER_RSP_OFFSET,
+    kernel_rsp = const cpu::KERNEL_RSP_OFFSET,
+    dispatch = sym super::syscall_dispatch,
+);
@@ -0,0 +1,32 @@
+use crate::print;
+use super::{ SyscallFrame, SyscallResult, SyscallError };
+use super::uaccess;
+
+/// 標準出力・標準エラー出力
+const STDOUT: u64 = 1;
+const STDERR: u64 = 2;
+
+/// 一度にコピーする大きさ
+const WRITE_CHUNK: usize = 256;
+
+/// write(fd, buf, len)
+/// 今のところ標準出力・標準エラー出力をコンソールに書き出すだけ
+pub fn sys_write(frame: &mut SyscallFrame) -> SyscallResult {
+    let (fd, buf, len) = (frame.rdi, frame.rsi, frame.rdx as usize);
+    if fd != STDOUT && fd != STDERR {
+        return Err(SyscallError::BadFd);
+    }
+    uaccess::check_user_range(buf, len, false)?;
+
+    let mut chunk = [0u8; WRITE_CHUNK];
+    let mut written = 0;
+    while written < len {
+        let n = core::cmp::min(WRITE_CHUNK, len - written);
+        uaccess::copy_from_user(&mut chunk[..n], buf + written as u64)?;
+        for &byte in &chunk[..n] {
+            print!("{}", byte as char);
+        }
+        written += n;
+    }
+    Ok(written as u64)
+}
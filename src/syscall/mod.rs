@@ -0,0 +1,135 @@
+use x86_64::VirtAddr;
+use x86_64::registers::model_specific::{ Efer, EferFlags, LStar, SFMask, Star };
+use x86_64::registers::rflags::RFlags;
+use crate::gdt;
+
+mod entry;
+mod uaccess;
+mod io;
+mod process;
+mod time;
+
+pub use entry::{ SyscallFrame, syscall_return };
+
+// システムコール番号
+// xv6 にあるものは xv6 と同じ番号を使う
+pub const SYS_FORK: usize = 1;
+pub const SYS_EXIT: usize = 2;
+pub const SYS_WAIT: usize = 3;
+pub const SYS_EXEC: usize = 7;
+pub const SYS_GETPID: usize = 11;
+pub const SYS_WRITE: usize = 16;
+pub const SYS_YIELD: usize = 22;
+pub const SYS_GETTID: usize = 23;
+pub const SYS_WAITPID: usize = 24;
+pub const SYS_NANOSLEEP: usize = 25;
+pub const SYS_CLOCK_GETTIME: usize = 26;
+pub const SYS_PS: usize = 27;
+pub const SYS_SETPRIORITY: usize = 28;
+pub const SYS_GETPRIORITY: usize = 29;
+pub const SYS_SETNICE: usize = 30;
+pub const SYS_GETNICE: usize = 31;
+pub const SYS_SETDEADLINE: usize = 32;
+pub const SYS_SETAFFINITY: usize = 33;
+pub const SYS_GETAFFINITY: usize = 34;
+
+/// システムコールテーブルの大きさ
+pub const NSYSCALL: usize = 64;
+
+/// システムコールのエラー
+/// ユーザには rax に負の値 (-errno) として返す
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    PermissionDenied = 1,   // EPERM
+    NoEntry = 2,            // ENOENT
+    NoSuchProcess = 3,      // ESRCH
+    TooBig = 7,             // E2BIG
+    NoExec = 8,             // ENOEXEC
+    BadFd = 9,              // EBADF
+    NoChild = 10,           // ECHILD
+    Again = 11,             // EAGAIN
+    NoMemory = 12,          // ENOMEM
+    Fault = 14,             // EFAULT
+    Busy = 16,              // EBUSY
+    InvalidArgument = 22,   // EINVAL
+    NoSys = 38,             // ENOSYS
+}
+
+impl SyscallError {
+    /// rax に入れる値に変換
+    pub fn as_u64(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// システムコールハンドラ
+/// 引数はユーザのレジスタを保存した SyscallFrame から取り出す
+type SyscallHandler = fn(&mut SyscallFrame) -> SyscallResult;
+
+/// システムコールテーブル
+static SYSCALL_TABLE: [Option<SyscallHandler>; NSYSCALL] = {
+    let mut table: [Option<SyscallHandler>; NSYSCALL] = [None; NSYSCALL];
+    table[SYS_FORK] = Some(process::sys_fork);
+    table[SYS_EXIT] = Some(process::sys_exit);
+    table[SYS_WAIT] = Some(process::sys_wait);
+    table[SYS_EXEC] = Some(process::sys_exec);
+    table[SYS_GETPID] = Some(process::sys_getpid);
+    table[SYS_WRITE] = Some(io::sys_write);
+    table[SYS_YIELD] = Some(process::sys_yield);
+    table[SYS_GETTID] = Some(process::sys_gettid);
+    table[SYS_WAITPID] = Some(process::sys_waitpid);
+    table[SYS_NANOSLEEP] = Some(time::sys_nanosleep);
+    table[SYS_CLOCK_GETTIME] = Some(time::sys_clock_gettime);
+    table[SYS_PS] = Some(process::sys_ps);
+    table[SYS_SETPRIORITY] = Some(process::sys_setpriority);
+    table[SYS_GETPRIORITY] = Some(process::sys_getpriority);
+    table[SYS_SETNICE] = Some(process::sys_setnice);
+    table[SYS_GETNICE] = Some(process::sys_getnice);
+    table[SYS_SETDEADLINE] = Some(process::sys_setdeadline);
+    table[SYS_SETAFFINITY] = Some(process::sys_setaffinity);
+    table[SYS_GETAFFINITY] = Some(process::sys_getaffinity);
+    table
+};
+
+/// この CPU で SYSCALL/SYSRET を有効化し、エントリポイントを登録する
+pub fn init() {
+    let selectors = gdt::selectors();
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.kernel_code_selector,
+        selectors.kernel_data_selector,
+    ).expect("invalid GDT layout for SYSCALL/SYSRET");
+
+    LStar::write(VirtAddr::new(entry::syscall_entry as *const () as u64));
+
+    // syscall 時に割り込み・トレース・方向フラグを落とす
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG);
+
+    unsafe {
+        Efer::update(|flags| *flags |= EferFlags::SYSTEM_CALL_EXTENSIONS);
+    }
+}
+
+/// システムコールの振り分け
+/// エントリスタブから割り込み禁止の状態で呼ばれる
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    x86_64::instructions::interrupts::enable();
+
+    let handler = SYSCALL_TABLE.get(frame.rax as usize).copied().flatten();
+    let result = match handler {
+        Some(handler) => handler(frame),
+        None => Err(SyscallError::NoSys),
+    };
+
+    // SYSRET までの間にユーザスタックで割り込みを受けないよう、割り込みを禁止して戻る
+    x86_64::instructions::interrupts::disable();
+
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(error) => error.as_u64(),
+    };
+}
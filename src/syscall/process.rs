@@ -0,0 +1,202 @@
+use alloc::vec::Vec;
+use crate::{ cpu, scheduler, thread };
+use crate::scheduler::edf::{ self, DeadlineParams };
+use crate::thread::uprocess::{ self, elf::Elf, exec::{ MAXARG, MAX_ARG_SIZE } };
+use super::{ SyscallFrame, SyscallResult, SyscallError };
+use super::uaccess;
+
+/// パス名の最大長
+const MAXPATH: usize = 128;
+
+/// waitpid のオプション: 終了した子がいなければすぐに戻る
+const WNOHANG: u64 = 1;
+
+/// fork()
+/// 親には子の pid を、子には 0 を返す
+/// カーネルスタックなどを確保できなければ ENOMEM、それ以外で失敗したら EAGAIN を返す
+pub fn sys_fork(frame: &mut SyscallFrame) -> SyscallResult {
+    let pid = uprocess::fork::fork(frame).map_err(|e| match e {
+        uprocess::fork::ForkError::NoMemory => SyscallError::NoMemory,
+        uprocess::fork::ForkError::Failed(_) => SyscallError::Again,
+    })?;
+    Ok(pid as u64)
+}
+
+/// exit(status)
+pub fn sys_exit(frame: &mut SyscallFrame) -> SyscallResult {
+    uprocess::exit::exit(uprocess::exit::exited_status(frame.rdi as i32));
+}
+
+/// wait(status)
+/// 子プロセスのどれかが終了するまで待ち、その pid を返す
+pub fn sys_wait(frame: &mut SyscallFrame) -> SyscallResult {
+    wait_child(None, frame.rdi, false)
+}
+
+/// waitpid(pid, status, options)
+/// pid が -1 ならどの子でもよい
+pub fn sys_waitpid(frame: &mut SyscallFrame) -> SyscallResult {
+    let target = match frame.rdi as i64 {
+        -1 => None,
+        pid if pid > 0 => Some(pid as usize),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+    wait_child(target, frame.rsi, frame.rdx & WNOHANG != 0)
+}
+
+/// 子プロセスの終了を待ち、status が NULL でなければステータスを書き込む
+/// nohang で終了した子がいなければ 0 を返す
+fn wait_child(target: Option<usize>, status: u64, nohang: bool) -> SyscallResult {
+    match uprocess::exit::wait(target, nohang) {
+        Ok(Some((pid, exit_status))) => {
+            if status != 0 {
+                uaccess::copy_to_user(status, &exit_status.to_le_bytes())?;
+            }
+            Ok(pid as u64)
+        }
+        Ok(None) => Ok(0),
+        Err(_) => Err(SyscallError::NoChild),
+    }
+}
+
+/// exec(path, argv, envp)
+/// 成功したら新しいプログラムのエントリポイントからユーザモードで再開する
+pub fn sys_exec(frame: &mut SyscallFrame) -> SyscallResult {
+    let path = uaccess::copy_str_from_user(frame.rdi, MAXPATH)?;
+    let argv = uaccess::copy_str_array_from_user(frame.rsi, MAXARG, MAX_ARG_SIZE)?;
+    let envp = uaccess::copy_str_array_from_user(frame.rdx, MAXARG, MAX_ARG_SIZE)?;
+    if argv.len() + envp.len() > MAXARG {
+        return Err(SyscallError::TooBig);
+    }
+    let strings_size: usize = argv.iter().chain(&envp).map(|s| s.len() + 1).sum();
+    if strings_size > MAX_ARG_SIZE {
+        return Err(SyscallError::TooBig);
+    }
+
+    let (path, image) = uprocess::programs::find(&path).ok_or(SyscallError::NoEntry)?;
+    let elf = Elf::parse(image).map_err(|_| SyscallError::NoExec)?;
+
+    let argv = argv.iter().map(|s| s.as_slice()).collect::<Vec<_>>();
+    let envp = envp.iter().map(|s| s.as_slice()).collect::<Vec<_>>();
+    let (entry, user_sp) = uprocess::exec::exec(uprocess::programs::basename(path), &elf, &argv, &envp).map_err(|_| SyscallError::NoMemory)?;
+
+    // SYSRET で新しいプログラムの先頭に戻る
+    *frame = SyscallFrame::new(entry, user_sp);
+    Ok(0)
+}
+
+/// yield()
+/// CPU を手放してスケジューラに戻る
+pub fn sys_yield(_frame: &mut SyscallFrame) -> SyscallResult {
+    scheduler::yield_from_context();
+    Ok(0)
+}
+
+/// getpid()
+pub fn sys_getpid(_frame: &mut SyscallFrame) -> SyscallResult {
+    let pid = uprocess::current_pid().ok_or(SyscallError::InvalidArgument)?;
+    Ok(pid as u64)
+}
+
+/// gettid()
+pub fn sys_gettid(_frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = thread::current_tid().ok_or(SyscallError::InvalidArgument)?;
+    Ok(tid as u64)
+}
+
+/// ps(buf, len)
+/// プロセスとスレッドの一覧を表にした文字列を buf に書き込む
+/// len に収まらない分は切り捨て、切り捨てる前の長さを返す
+pub fn sys_ps(frame: &mut SyscallFrame) -> SyscallResult {
+    let (buf, len) = (frame.rdi, frame.rsi as usize);
+    let table = thread::stat::table();
+    let n = core::cmp::min(len, table.len());
+    uaccess::copy_to_user(buf, &table.as_bytes()[..n])?;
+    Ok(table.len() as u64)
+}
+
+/// setpriority(tid, priority)
+/// tid が -1 なら呼び出したスレッド。同じプロセスのスレッドだけを変えられる
+/// KERNEL_PRIORITY はカーネルスレッド用なので、指定すると EPERM
+pub fn sys_setpriority(frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = own_thread(frame.rdi)?;
+    let priority = frame.rsi as usize;
+    if priority == thread::KERNEL_PRIORITY {
+        return Err(SyscallError::PermissionDenied);
+    }
+    scheduler::set_priority(tid, priority).map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(0)
+}
+
+/// getpriority(tid)
+/// tid が -1 なら呼び出したスレッド
+pub fn sys_getpriority(frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = own_thread(frame.rdi)?;
+    let priority = scheduler::priority(tid).map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(priority as u64)
+}
+
+/// setnice(tid, nice)
+/// tid が -1 なら呼び出したスレッド。同じプロセスのスレッドだけを変えられる
+pub fn sys_setnice(frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = own_thread(frame.rdi)?;
+    let nice = i8::try_from(frame.rsi as i64).map_err(|_| SyscallError::InvalidArgument)?;
+    scheduler::set_nice(tid, nice).map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(0)
+}
+
+/// getnice(tid)
+/// 負の値はエラーと区別できないので、Linux の getpriority と同じく 20 - nice (1 ～ 40) を返す
+pub fn sys_getnice(frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = own_thread(frame.rdi)?;
+    let nice = scheduler::nice(tid).map_err(|_| SyscallError::InvalidArgument)?;
+    Ok((20 - nice as i64) as u64)
+}
+
+/// setdeadline(tid, runtime, deadline, period)
+/// 時間は ns。runtime が 0 ならベストエフォートのスレッドに戻す
+/// 受け入れ制御で断られたら EBUSY を返す
+pub fn sys_setdeadline(frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = own_thread(frame.rdi)?;
+    let params = (frame.rsi != 0).then_some(DeadlineParams {
+        runtime: frame.rsi,
+        deadline: frame.rdx,
+        period: frame.r10,
+    });
+    edf::set_deadline(tid, params).map_err(|e| match e {
+        edf::DeadlineError::NotSchedulable => SyscallError::Busy,
+        _ => SyscallError::InvalidArgument,
+    })?;
+    Ok(0)
+}
+
+/// setaffinity(tid, mask)
+/// mask のビット i が立っていれば CPU i で実行できる。登録済みの CPU を 1 つも含まなければ EINVAL
+pub fn sys_setaffinity(frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = own_thread(frame.rdi)?;
+    scheduler::set_affinity(tid, frame.rsi).map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(0)
+}
+
+/// getaffinity(tid)
+/// 実行できる CPU のマスクのうち、登録済みの CPU の分を返す
+pub fn sys_getaffinity(frame: &mut SyscallFrame) -> SyscallResult {
+    let tid = own_thread(frame.rdi)?;
+    let mask = scheduler::affinity(tid).map_err(|_| SyscallError::InvalidArgument)?;
+    Ok(mask & cpu::all_cpus_mask())
+}
+
+/// 引数の tid を、呼び出したプロセスのスレッドの Thread ID にする
+fn own_thread(tid: u64) -> Result<usize, SyscallError> {
+    let current = thread::current_tid().ok_or(SyscallError::InvalidArgument)?;
+    if tid as i64 == -1 {
+        return Ok(current);
+    }
+    let tid = tid as usize;
+    let pid = uprocess::current_pid();
+    let owner = thread::THREAD_TABLE.lock().get(tid).map(|thread| thread.pid);
+    if pid.is_none() || owner != Some(pid) {
+        return Err(SyscallError::NoSuchProcess);
+    }
+    Ok(tid)
+}
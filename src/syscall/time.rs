@@ -0,0 +1,72 @@
+use core::time::Duration;
+use crate::{ thread, time };
+use super::{ SyscallFrame, SyscallResult, SyscallError };
+use super::uaccess;
+
+/// clock_gettime の時計
+/// 実時間の時計はまだないので、起動からの単調時計だけを提供する
+const CLOCK_MONOTONIC: u64 = 1;
+const CLOCK_BOOTTIME: u64 = 7;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+/// ユーザとやり取りする時間 (struct timespec)
+struct Timespec {
+    tv_sec: i64,
+    tv_nsec: i64,
+}
+
+impl Timespec {
+    const SIZE: usize = 16;
+
+    fn read_from_user(src: u64) -> Result<Self, SyscallError> {
+        let mut bytes = [0u8; Self::SIZE];
+        uaccess::copy_from_user(&mut bytes, src)?;
+        Ok(Timespec {
+            tv_sec: i64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            tv_nsec: i64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        })
+    }
+
+    fn write_to_user(&self, dst: u64) -> Result<(), SyscallError> {
+        let mut bytes = [0u8; Self::SIZE];
+        bytes[0..8].copy_from_slice(&self.tv_sec.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.tv_nsec.to_le_bytes());
+        uaccess::copy_to_user(dst, &bytes)
+    }
+
+    fn from_nanos(nanos: u64) -> Self {
+        Timespec {
+            tv_sec: (nanos / NANOS_PER_SEC as u64) as i64,
+            tv_nsec: (nanos % NANOS_PER_SEC as u64) as i64,
+        }
+    }
+
+    /// 負の値や範囲外の tv_nsec なら None
+    fn to_duration(&self) -> Option<Duration> {
+        if self.tv_sec < 0 || !(0..NANOS_PER_SEC).contains(&self.tv_nsec) {
+            return None;
+        }
+        Some(Duration::new(self.tv_sec as u64, self.tv_nsec as u32))
+    }
+}
+
+/// nanosleep(req, rem)
+/// シグナルで中断されることはないので、rem には書き込まない
+pub fn sys_nanosleep(frame: &mut SyscallFrame) -> SyscallResult {
+    let duration = Timespec::read_from_user(frame.rdi)?
+        .to_duration()
+        .ok_or(SyscallError::InvalidArgument)?;
+    thread::sleep_for(duration);
+    Ok(0)
+}
+
+/// clock_gettime(clockid, tp)
+pub fn sys_clock_gettime(frame: &mut SyscallFrame) -> SyscallResult {
+    match frame.rdi {
+        CLOCK_MONOTONIC | CLOCK_BOOTTIME => {}
+        _ => return Err(SyscallError::InvalidArgument),
+    }
+    Timespec::from_nanos(time::nanos()).write_to_user(frame.rsi)?;
+    Ok(0)
+}
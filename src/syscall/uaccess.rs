@@ -0,0 +1,99 @@
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+use crate::memory;
+use crate::thread::uprocess::{ self, USER_SPACE_START, USER_SPACE_END, address_space::COW };
+use super::SyscallError;
+
+/// [addr, addr + len) がユーザからアクセス可能か確認する
+/// write が true のときは書き込み可能であることも確認する
+pub fn check_user_range(addr: u64, len: usize, write: bool) -> Result<(), SyscallError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr.checked_add(len as u64).ok_or(SyscallError::Fault)?;
+    if addr < USER_SPACE_START || end > USER_SPACE_END {
+        return Err(SyscallError::Fault);
+    }
+
+    // 範囲に含まれる全ページを確認
+    let mut page = addr & !0xfff;
+    while page < end {
+        let flags = memory::user_page_flags(VirtAddr::new(page)).ok_or(SyscallError::Fault)?;
+        if write && !flags.contains(PageTableFlags::WRITABLE) {
+            // カーネルからの書き込みでは COW のページフォルトを処理しないので、ここでコピーしておく
+            if !flags.contains(COW) || !uprocess::handle_cow_fault(VirtAddr::new(page)) {
+                return Err(SyscallError::Fault);
+            }
+        }
+        page += 4096;
+    }
+    Ok(())
+}
+
+/// ユーザ空間からカーネルのバッファへコピーする
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), SyscallError> {
+    check_user_range(src, dst.len(), false)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len());
+    }
+    Ok(())
+}
+
+/// カーネルのバッファからユーザ空間へコピーする
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), SyscallError> {
+    check_user_range(dst, src.len(), true)?;
+    unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len());
+    }
+    Ok(())
+}
+
+/// ユーザ空間から u64 を読む
+pub fn read_u64_from_user(src: u64) -> Result<u64, SyscallError> {
+    let mut bytes = [0u8; 8];
+    copy_from_user(&mut bytes, src)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// ユーザ空間から NUL 終端の文字列をコピーする (NUL は含めない)
+/// max_len バイト以内に NUL がなければ TooBig を返す
+pub fn copy_str_from_user(src: u64, max_len: usize) -> Result<Vec<u8>, SyscallError> {
+    let mut s = Vec::new();
+    let mut addr = src;
+    loop {
+        // ページ境界までをまとめて確認する
+        let n = (4096 - (addr & 0xfff)) as usize;
+        check_user_range(addr, n, false)?;
+        for i in 0..n {
+            let byte = unsafe { *((addr + i as u64) as *const u8) };
+            if byte == 0 {
+                return Ok(s);
+            }
+            if s.len() >= max_len {
+                return Err(SyscallError::TooBig);
+            }
+            s.push(byte);
+        }
+        addr += n as u64;
+    }
+}
+
+/// ユーザ空間の NULL 終端の文字列ポインタ配列 (argv, envp) をコピーする
+/// src が NULL なら空の配列とみなす
+pub fn copy_str_array_from_user(src: u64, max_count: usize, max_len: usize) -> Result<Vec<Vec<u8>>, SyscallError> {
+    let mut array = Vec::new();
+    if src == 0 {
+        return Ok(array);
+    }
+    loop {
+        let ptr = read_u64_from_user(src + array.len() as u64 * 8)?;
+        if ptr == 0 {
+            return Ok(array);
+        }
+        if array.len() >= max_count {
+            return Err(SyscallError::TooBig);
+        }
+        array.push(copy_str_from_user(ptr, max_len)?);
+    }
+}
@@ -1,12 +1,16 @@
 use super::{ Task, TaskId, TASK_MAX };
 use alloc::{ collections::BTreeMap, sync::Arc, task::Wake };
 use core::task::{ Waker, Context, Poll };
+use core::sync::atomic::Ordering;
 use crossbeam_queue::ArrayQueue;
+use crate::{ cpu, scheduler };
+use crate::libbackend::lock::IrqMutex;
 
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
     task_queue: Arc<ArrayQueue<TaskId>>,    // 複数の所有者(executorとwaker)間で値の所有権共有が可能
     waker_cache: BTreeMap<TaskId, Waker>,
+    idle: Arc<IrqMutex<()>>,                // スレッドで眠るときに、起こされるのを取りこぼさないためのロック
 }
 
 impl Executor {
@@ -15,6 +19,7 @@ impl Executor {
             tasks: BTreeMap::new(),
             task_queue: Arc::new(ArrayQueue::new(TASK_MAX)),
             waker_cache: BTreeMap::new(),
+            idle: Arc::new(IrqMutex::new(())),
         }
     }
 
@@ -39,6 +44,7 @@ impl Executor {
             tasks,
             task_queue,
             waker_cache,
+            idle,
         } = self;
 
         while let Some(task_id) = task_queue.pop() {
@@ -46,7 +52,7 @@ impl Executor {
                 Some(task) => task,
                 None => continue,
             };
-            let waker = waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
+            let waker = waker_cache.entry(task_id).or_insert_with(|| TaskWaker::new(task_id, task_queue.clone(), idle.clone()));
             let mut context = Context::from_waker(waker);
             match task.poll(&mut context) {
                 // タスク完了の場合、task と waker を除去
@@ -60,6 +66,15 @@ impl Executor {
     }
 
     fn sleep_if_idle(&self) {
+        // スレッドで動いているときは、タスクが起こされるまでスケジューラで眠って CPU を譲る
+        if cpu::current_tid().is_some() {
+            let idle = self.idle.lock();
+            if self.task_queue.is_empty() {
+                scheduler::sleep(wake_chan(&self.task_queue), idle);
+            }
+            return;
+        }
+
         // ここで割り込みが起こった場合の対策:
         // CPU 割り込みを無効化し、hlt 命令と一緒にアトミックに再度有効化
         use x86_64::instructions::interrupts::{ self, enable_and_hlt };
@@ -75,21 +90,35 @@ impl Executor {
     }
 }
 
+/// スレッドで眠っている Executor を起こすときのチャネル
+fn wake_chan(task_queue: &Arc<ArrayQueue<TaskId>>) -> usize {
+    Arc::as_ptr(task_queue) as usize
+}
+
 struct TaskWaker {
     task_id: TaskId,
     task_queue: Arc<ArrayQueue<TaskId>>,
+    idle: Arc<IrqMutex<()>>,
 }
 
 impl TaskWaker {
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
+    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>, idle: Arc<IrqMutex<()>>) -> Waker {
         Waker::from(Arc::new(TaskWaker {
             task_id,
             task_queue,
+            idle,
         }))
     }
 
     fn wake_task(&self) {
         self.task_queue.push(self.task_id).expect("task_queue full");
+
+        // Executor が空なのを確かめてから眠るまでの間は idle をロックしているので、
+        // ロックを取り直してから起こせば取りこぼさない
+        drop(self.idle.lock());
+        if scheduler::SCHEDULER_STARTED.load(Ordering::Relaxed) {
+            scheduler::wakeup(wake_chan(&self.task_queue));
+        }
     }
 }
 
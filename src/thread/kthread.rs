@@ -1,21 +1,30 @@
-use super::{ STACK_SIZE, THREAD_TABLE, ThreadState };
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use crate::libbackend::lock::IrqMutex;
+use crate::{ scheduler, time };
+use super::{ Thread, THREAD_TABLE, ThreadState };
 
 pub const NTHREAD: usize = 64;
 
+/// reap したカーネルスレッドのカーネルスタック (0 は空き)
+/// スケジューラは THREAD_TABLE を持ったまま reap するので、ここに印を付けるだけにしておき、
+/// ヒープへの解放はロックを外してから free_reaped で行う
+static REAPED_KSTACKS: IrqMutex<[u64; NTHREAD]> = IrqMutex::new([0; NTHREAD]);
+
 /// カーネルスレッド作成
-pub fn create_kernel_thread(entry: fn() -> !) {
+/// 作成したスレッドの Thread ID を返す
+pub fn create_kernel_thread(name: &'static str, entry: fn() -> !) -> Result<usize, &'static str> {
     // スレッド ID を確保
-    let tid = super::next_tid().expect("Thread table is full");
+    let tid = super::next_tid().ok_or("Thread table is full")?;
 
     // スタックを作成
-    let stack = unsafe {
-        let layout = alloc::alloc::Layout::from_size_align(STACK_SIZE, 16).unwrap();
-        alloc::alloc::alloc(layout)
-    };
-    let stack_top = stack as u64 + STACK_SIZE as u64;
+    let stack_top = super::alloc_kstack().inspect_err(|_| super::discard_thread(tid))?;
 
     let mut table = THREAD_TABLE.lock();
     table[tid].tid = tid;
+    table[tid].name = name;
+    table[tid].start_ticks = time::ticks();
     table[tid].state = ThreadState::Runnable;
     table[tid].kstack = stack_top;
 
@@ -23,4 +32,178 @@ pub fn create_kernel_thread(entry: fn() -> !) {
     table[tid].context.rsp = stack_top;
     table[tid].context.rip = entry as u64;
     table[tid].context.rflags = 0x200;  // IF (Interrupt Flag) を有効化
+    drop(table);
+
+    scheduler::ready(tid);
+    Ok(tid)
+}
+
+/// spawn したスレッドの結果を受け渡す場所
+/// スレッドが結果を書き込んでから、このアドレスのチャネルで join を起こす
+struct Packet<T> {
+    result: IrqMutex<Option<T>>,
+}
+
+impl<T> Packet<T> {
+    fn chan(&self) -> usize {
+        self as *const _ as usize
+    }
+}
+
+/// spawn したカーネルスレッドのハンドル
+/// join せずにドロップすると、スレッドは切り離されて結果は捨てられる
+pub struct JoinHandle<T> {
+    tid: usize,
+    packet: Arc<Packet<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// スレッドの Thread ID
+    pub fn tid(&self) -> usize {
+        self.tid
+    }
+
+    /// スレッドが終了したか
+    pub fn is_finished(&self) -> bool {
+        self.packet.result.lock().is_some()
+    }
+
+    /// スレッドの終了を待ち、クロージャの戻り値を返す
+    /// 終了するまでスケジューラで眠る
+    pub fn join(self) -> T {
+        loop {
+            let mut result = self.packet.result.lock();
+            if let Some(value) = result.take() {
+                return value;
+            }
+            scheduler::sleep(self.packet.chan(), result);
+        }
+    }
+}
+
+/// クロージャを実行するカーネルスレッドを作成する
+/// クロージャから戻るとスレッドは Zombie になり、スケジューラがカーネルスタックとスロットを解放する
+pub fn spawn<F, T>(name: &'static str, f: F) -> Result<JoinHandle<T>, &'static str>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let packet = Arc::new(Packet { result: IrqMutex::new(None) });
+    let thread_packet = packet.clone();
+    let main: Box<dyn FnOnce() + Send> = Box::new(move || {
+        let value = f();
+        *thread_packet.result.lock() = Some(value);
+        scheduler::wakeup(thread_packet.chan());
+    });
+
+    // スレッド ID を確保
+    let tid = super::next_tid().ok_or("Thread table is full")?;
+
+    // スタックを作成
+    let stack_top = super::alloc_kstack().inspect_err(|_| super::discard_thread(tid))?;
+
+    // クロージャは r12 で kernel_thread_start に渡す
+    // Box<dyn FnOnce> は fat pointer なので、もう一度 Box に包んで 1 ワードにする
+    let arg = Box::into_raw(Box::new(main));
+
+    let mut table = THREAD_TABLE.lock();
+    table[tid].tid = tid;
+    table[tid].name = name;
+    table[tid].start_ticks = time::ticks();
+    table[tid].state = ThreadState::Runnable;
+    table[tid].kstack = stack_top;
+
+    // コンテキストを初期化する
+    table[tid].context.rsp = stack_top;
+    table[tid].context.rip = kernel_thread_start as *const () as u64;
+    table[tid].context.r12 = arg as u64;
+    table[tid].context.rflags = 0x200;  // IF (Interrupt Flag) を有効化
+    drop(table);
+
+    scheduler::ready(tid);
+    Ok(JoinHandle { tid, packet })
+}
+
+/// 終了したカーネルスレッドのスロットを解放し、カーネルスタックを解放待ちにする
+/// スケジューラが、スレッドのコンテキストを保存し終えてから呼ぶ
+/// ユーザスレッドは親が wait で回収するので、ここでは解放しない
+pub fn reap(thread: &mut Thread) -> bool {
+    if thread.state != ThreadState::Zombie || thread.pid.is_some() {
+        return false;
+    }
+    // カーネルスタックを持たないスレッド (kstack が 0) はスロットだけ解放する
+    if thread.kstack != 0 {
+        let mut reaped = REAPED_KSTACKS.lock();
+        let slot = reaped.iter().position(|&kstack| kstack == 0).expect("too many reaped kernel stacks");
+        reaped[slot] = thread.kstack;
+    }
+    *thread = Thread::new();
+    true
+}
+
+/// reap したカーネルスタックをヒープに返す
+/// ロックを持たない状態で、スケジューラから呼ぶ
+pub fn free_reaped() {
+    for kstack in take_reaped().into_iter().filter(|&kstack| kstack != 0) {
+        super::free_kstack(kstack);
+    }
+}
+
+/// reap したカーネルスタックを取り出す
+fn take_reaped() -> [u64; NTHREAD] {
+    core::mem::replace(&mut *REAPED_KSTACKS.lock(), [0; NTHREAD])
+}
+
+unsafe extern "C" {
+    fn kernel_thread_start() -> !;
+}
+
+// spawn したスレッドの最初の命令
+// r12 に入っているクロージャを引数にして kernel_thread_main を呼ぶ
+global_asm!(
+r#"
+.globl kernel_thread_start
+kernel_thread_start:
+    mov rdi, r12
+    call {main}
+    ud2
+"#,
+    main = sym kernel_thread_main,
+);
+
+extern "C" fn kernel_thread_main(arg: *mut Box<dyn FnOnce() + Send>) -> ! {
+    let main = unsafe { Box::from_raw(arg) };
+    main();
+    scheduler::exit_from_context();
+}
+
+#[test_case]
+fn test_reap_only_kernel_zombies() {
+    // ユーザスレッドは wait で回収するので残す
+    let mut thread = Thread::new();
+    thread.state = ThreadState::Zombie;
+    thread.pid = Some(1);
+    assert!(!reap(&mut thread));
+    assert_eq!(thread.state, ThreadState::Zombie);
+
+    // 終了していないカーネルスレッドも残す
+    thread.pid = None;
+    thread.state = ThreadState::Running;
+    assert!(!reap(&mut thread));
+    assert_eq!(thread.state, ThreadState::Running);
+
+    // 終了したカーネルスレッドはスロットを解放する
+    // kstack が 0 ならヒープに返すものはない
+    thread.state = ThreadState::Zombie;
+    thread.kstack = 0;
+    assert!(reap(&mut thread));
+    assert_eq!(thread.state, ThreadState::Unused);
+
+    // カーネルスタックは reap では解放せず、解放待ちにする
+    // ダミーのアドレスなので、ヒープに返さずに取り出す
+    thread.state = ThreadState::Zombie;
+    thread.kstack = 0xdead_0000;
+    assert!(reap(&mut thread));
+    assert_eq!(thread.state, ThreadState::Unused);
+    assert!(take_reaped().contains(&0xdead_0000));
 }
@@ -1,8 +1,10 @@
 use crate::scheduler;
 use scheduler::context::Context;
-use crate::cpu;
+use scheduler::edf::DeadlineParams;
+use crate::{ cpu, fpu, time };
 
 pub mod kthread;
+pub mod stat;
 pub mod uprocess;
 
 extern crate alloc;
@@ -23,45 +25,146 @@ pub enum ThreadState {
 #[derive(Debug, Clone, Copy)]
 pub struct Thread {
     pub tid: usize,             // Thread ID
+    pub name: &'static str,     // スレッド名
     pub state: ThreadState,     // スレッドの状態
     pub context: Context,       // スレッドのコンテキスト
     pub kstack: u64,            // このスレッド用のカーネルスタック
+    pub pid: Option<usize>,     // 所属するプロセス (カーネルスレッドは None)
+    pub chan: Option<usize>,    // Sleeping のとき、待っているチャネル
+    pub on_cpu: bool,           // CPU で実行中か (スケジューラに戻ってコンテキストを保存し終えるまで true)
+    pub start_ticks: u64,       // 作成したときのティック
+    pub cpu_ticks: u64,         // 実行中にタイマ割り込みを受けたティック数
+    pub voluntary_switches: u64,    // Sleeping・終了でスケジューラに戻った回数
+    pub involuntary_switches: u64,  // Runnable のまま (プリエンプト・yield) スケジューラに戻った回数
+    pub last_cpu: Option<usize>,    // 最後に実行した CPU
+    pub priority: usize,        // 優先度 (0 が最も高い)
+    pub nice: i8,               // CPU 時間の取り分 (MIN_NICE ～ MAX_NICE、小さいほど多い)
+    pub deadline: Option<DeadlineParams>,   // リアルタイムスレッドのパラメータ (None ならベストエフォート)
+    pub deadline_misses: u64,   // デッドラインに間に合わなかった周期の数
+    pub affinity: u64,          // 実行できる CPU (ビット i が CPU ID i)
 }
 
 impl Thread {
     pub fn new() -> Self {
         Thread {
             tid: 0,
+            name: "",
             state: ThreadState::Unused,
             context: Context::new(),
             kstack: 0,
+            pid: None,
+            chan: None,
+            on_cpu: false,
+            start_ticks: 0,
+            cpu_ticks: 0,
+            voluntary_switches: 0,
+            involuntary_switches: 0,
+            last_cpu: None,
+            priority: DEFAULT_PRIORITY,
+            nice: 0,
+            deadline: None,
+            deadline_misses: 0,
+            affinity: ALL_CPUS,
         }
     }
+
+    /// CPU ID cpu で実行できるか
+    pub fn can_run_on(&self, cpu: usize) -> bool {
+        self.affinity & (1 << cpu) != 0
+    }
 }
 
 pub const NTHREAD: usize = 64;
-use spin::Mutex;
+
+/// 優先度の段階数
+/// 0 が最も高く、NPRIORITY - 1 が最も低い
+pub const NPRIORITY: usize = 8;
+
+/// 作成したスレッドの優先度
+pub const DEFAULT_PRIORITY: usize = NPRIORITY / 2;
+
+/// カーネルスレッドだけが使える優先度
+/// ユーザプロセスのスレッドは setpriority でこの優先度にできない
+pub const KERNEL_PRIORITY: usize = 0;
+
+/// すべての CPU で実行できる affinity
+pub const ALL_CPUS: u64 = u64::MAX;
+
+/// nice の範囲
+pub const MIN_NICE: i8 = -20;
+pub const MAX_NICE: i8 = 19;
+use crate::libbackend::lock::IrqMutex;
 use lazy_static::lazy_static;
 
 lazy_static! {
-    pub static ref THREAD_TABLE: Mutex<[Thread; NTHREAD]> = {
-        Mutex::new([Thread::new(); NTHREAD])
+    /// 割り込みハンドラからの wakeup でもロックするので、IrqMutex で守る
+    /// PROCESS_TABLE と一緒に取るときは PROCESS_TABLE → THREAD_TABLE の順に取る
+    pub static ref THREAD_TABLE: IrqMutex<[Thread; NTHREAD]> = {
+        IrqMutex::new([Thread::new(); NTHREAD])
     };
 }
 
 /// スレッド ID 決定
+/// 確保したスロットは Embryo にして、他のスレッド作成と重ならないようにする
 pub fn next_tid() -> Option<usize> {
-    let table = THREAD_TABLE.lock();
+    let mut table = THREAD_TABLE.lock();
     for i in 0..NTHREAD-1 {
         if table[i].state == ThreadState::Unused {
+            table[i].state = ThreadState::Embryo;
             return Some(i);
         }
     }
     None
 }
 
+/// カーネルスタックを確保し、その先頭 (上端) のアドレスを返す
+/// ヒープが足りなければエラーを返す
+pub fn alloc_kstack() -> Result<u64, &'static str> {
+    let stack = unsafe {
+        let layout = alloc::alloc::Layout::from_size_align(STACK_SIZE, 16).unwrap();
+        alloc::alloc::alloc(layout)
+    };
+    if stack.is_null() {
+        return Err("kernel stack allocation failed");
+    }
+    Ok(stack as u64 + STACK_SIZE as u64)
+}
+
+/// alloc_kstack で確保したカーネルスタックを解放する
+pub fn free_kstack(stack_top: u64) {
+    unsafe {
+        let layout = alloc::alloc::Layout::from_size_align(STACK_SIZE, 16).unwrap();
+        alloc::alloc::dealloc((stack_top - STACK_SIZE as u64) as *mut u8, layout);
+    }
+}
+
+/// まだ実行していないスレッドのスロットを空け、カーネルスタックと FPU の保存領域を解放する
+/// スレッドの作成が途中で失敗したときに使う
+/// ヒープへの解放は THREAD_TABLE のロックを外してから行う
+pub fn discard_thread(tid: usize) {
+    let thread = core::mem::replace(&mut THREAD_TABLE.lock()[tid], Thread::new());
+    if thread.kstack != 0 {
+        free_kstack(thread.kstack);
+    }
+    if thread.context.fpu != 0 {
+        fpu::free_state(thread.context.fpu);
+    }
+}
+
+/// 実行中のスレッドを duration の間 Sleeping にする
+/// タイマ割り込みの間隔で丸めるので、少なくとも duration は眠る
+pub fn sleep_for(duration: core::time::Duration) {
+    time::sleep_ticks(time::duration_to_ticks(duration));
+}
+
+/// タイマ割り込みごとに、この CPU で実行中のスレッドの CPU 時間を数える
+pub fn account_tick() {
+    if let Some(tid) = current_tid() {
+        THREAD_TABLE.lock()[tid].cpu_ticks += 1;
+    }
+}
+
 /// 現在実行中のスレッドの tid を取得
 pub fn current_tid() -> Option<usize> {
-    let cpu = cpu::CPU.lock();
-    cpu.current_tid
+    cpu::current_tid()
 }
@@ -0,0 +1,163 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{ self, Debug, Write };
+use crate::time;
+use super::{ Thread, ThreadState, THREAD_TABLE, NTHREAD };
+use super::uprocess::{ Process, PROCESS_TABLE, NPROCESS };
+
+/// プロセスとスレッドの一覧を表にして書き出す (ps)
+/// 表示する間はロックを持たないよう、先に両方のテーブルを写し取る
+pub fn write_table(out: &mut impl Write) -> fmt::Result {
+    let (processes, threads) = snapshot();
+    write_rows(out, &processes, &threads, time::tick_nanos())
+}
+
+/// write_table の内容を文字列で返す
+pub fn table() -> String {
+    let mut s = String::new();
+    write_table(&mut s).expect("formatting to a String failed");
+    s
+}
+
+/// 使用中のプロセスとスレッドを写し取る
+/// ロック中にヒープを確保しないよう、テーブルの大きさ分を先に確保してから
+/// PROCESS_TABLE → THREAD_TABLE の順にロックする
+fn snapshot() -> (Vec<Process>, Vec<Thread>) {
+    let mut processes = Vec::with_capacity(NPROCESS);
+    let mut threads = Vec::with_capacity(NTHREAD);
+
+    let process_table = PROCESS_TABLE.lock();
+    let thread_table = THREAD_TABLE.lock();
+    processes.extend(process_table.iter().flatten().copied());
+    threads.extend(thread_table.iter().filter(|thread| thread.state != ThreadState::Unused).copied());
+    (processes, threads)
+}
+
+/// 写し取ったプロセスとスレッドを表にして書き出す
+fn write_rows(out: &mut impl Write, processes: &[Process], threads: &[Thread], tick_nanos: u64) -> fmt::Result {
+    writeln!(out, "  PID  PPID STATE    THREADS")?;
+    for process in processes {
+        write!(out, "{:>5} ", process.pid)?;
+        match process.parent {
+            Some(parent) => write!(out, "{:>5} ", parent)?,
+            None => write!(out, "{:>5} ", "-")?,
+        }
+        writeln!(out, "{:<8} {:>7}", Name(process.state), process.nthread)?;
+    }
+
+    writeln!(out)?;
+    writeln!(out, "  TID   PID NAME             STATE    PRI  NI CPU     START      TIME    VCSW   IVCSW  MISS")?;
+    for thread in threads {
+        write!(out, "{:>5} ", thread.tid)?;
+        match thread.pid {
+            Some(pid) => write!(out, "{:>5} ", pid)?,
+            None => write!(out, "{:>5} ", "-")?,
+        }
+        write!(out, "{:<16.16} {:<8} ", thread.name, Name(thread.state))?;
+        write!(out, "{:>3} {:>3} ", thread.priority, thread.nice)?;
+        match thread.last_cpu {
+            Some(cpu) => write!(out, "{:>3} ", cpu)?,
+            None => write!(out, "{:>3} ", "-")?,
+        }
+        writeln!(
+            out,
+            "{:>9} {:>9} {:>7} {:>7} {:>5}",
+            Seconds(thread.start_ticks * tick_nanos),
+            Seconds(thread.cpu_ticks * tick_nanos),
+            thread.voluntary_switches,
+            thread.involuntary_switches,
+            thread.deadline_misses,
+        )?;
+    }
+    Ok(())
+}
+
+/// ns を秒.ミリ秒で表示する
+struct Seconds(u64);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let millis = self.0 / 1_000_000;
+        let mut buf = FixedBuf::<24>::new();
+        write!(buf, "{}.{:03}", millis / 1000, millis % 1000)?;
+        f.pad(buf.as_str())
+    }
+}
+
+/// 状態の名前 (Debug 表示) を幅を揃えて表示する
+struct Name<T: Debug>(T);
+
+impl<T: Debug> fmt::Display for Name<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut buf = FixedBuf::<16>::new();
+        write!(buf, "{:?}", self.0)?;
+        f.pad(buf.as_str())
+    }
+}
+
+/// ヒープを使わずに書式化するための固定長バッファ
+/// 入りきらない書き込みはエラーにする
+struct FixedBuf<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedBuf<N> {
+    fn new() -> Self {
+        FixedBuf { buf: [0; N], len: 0 }
+    }
+
+    fn as_str(&self) -> &str {
+        // write_str で受け取った str だけを書き込んでいるので UTF-8 として正しい
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl<const N: usize> Write for FixedBuf<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > N {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_seconds_format() {
+    let mut buf = FixedBuf::<32>::new();
+    write!(buf, "[{}] [{:>9}]", Seconds(0), Seconds(12_345_678_901)).unwrap();
+    assert_eq!(buf.as_str(), "[0.000] [   12.345]");
+}
+
+#[test_case]
+fn test_write_rows() {
+    use x86_64::{ PhysAddr, structures::paging::PhysFrame };
+    use super::uprocess::address_space::AddressSpace;
+
+    let address_space = AddressSpace { pml4: PhysFrame::containing_address(PhysAddr::new(0x1000)) };
+    let mut process = Process::new(1, None, address_space);
+    process.nthread = 1;
+
+    let mut thread = Thread::new();
+    thread.tid = 2;
+    thread.pid = Some(1);
+    thread.name = "init";
+    thread.state = ThreadState::Running;
+    thread.last_cpu = Some(0);
+    thread.start_ticks = 3;
+    thread.cpu_ticks = 1500;
+    thread.voluntary_switches = 7;
+
+    let mut buf = FixedBuf::<512>::new();
+    write_rows(&mut buf, &[process], &[thread], 1_000_000).unwrap();
+    let mut lines = buf.as_str().lines();
+    assert_eq!(lines.next(), Some("  PID  PPID STATE    THREADS"));
+    assert_eq!(lines.next(), Some("    1     - Running        1"));
+    assert_eq!(lines.next(), Some(""));
+    assert_eq!(lines.next(), Some("  TID   PID NAME             STATE    PRI  NI CPU     START      TIME    VCSW   IVCSW  MISS"));
+    assert_eq!(lines.next(), Some("    2     1 init             Running    4   0   0     0.003     1.500       7       0     0"));
+    assert_eq!(lines.next(), None);
+}
@@ -0,0 +1,293 @@
+use x86_64::VirtAddr;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{ FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PageTableIndex, PhysFrame, Translate };
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::mapper::{ MappedFrame, TranslateResult };
+use crate::memory::{ self, GlobalFrameAllocator };
+use super::{ USER_SPACE_START, USER_SPACE_END };
+
+/// Copy-on-Write ページの印 (OS が自由に使えるビット)
+pub const COW: PageTableFlags = PageTableFlags::BIT_9;
+
+/// プロセスのアドレス空間
+/// level4 テーブルのうちユーザ領域以外のエントリはカーネルのものをそのまま共有する
+/// カーネルの level3 テーブルは init_kernel_tables で起動時にすべて用意するので、後からのカーネルのマッピングも共有される
+#[derive(Debug, Clone, Copy)]
+pub struct AddressSpace {
+    pub pml4: PhysFrame,
+}
+
+impl AddressSpace {
+    /// カーネル部分だけをマップした新しいアドレス空間を作る
+    pub fn new() -> Result<Self, &'static str> {
+        let frame = GlobalFrameAllocator.allocate_frame().ok_or("frame alloc failed")?;
+
+        let table = unsafe { &mut *page_table_ptr(frame) };
+        let kernel_table = unsafe { &*page_table_ptr(memory::kernel_pml4()) };
+        for (i, entry) in table.iter_mut().enumerate() {
+            if is_user_entry(i) {
+                entry.set_unused();
+            }
+            else {
+                *entry = kernel_table[i].clone();
+            }
+        }
+
+        Ok(AddressSpace { pml4: frame })
+    }
+
+    /// このアドレス空間を操作する Mapper
+    /// 返り値を使っている間は同じアドレス空間に対する Mapper を他に作ってはならない
+    pub fn mapper(&self) -> OffsetPageTable<'static> {
+        let table = unsafe { &mut *page_table_ptr(self.pml4) };
+        unsafe { OffsetPageTable::new(table, memory::physical_memory_offset()) }
+    }
+
+    /// ゼロクリアしたフレームを割り当ててユーザページとしてマップし、そのフレームを返す
+    pub fn map_user_page(&self, page: Page, flags: PageTableFlags) -> Result<PhysFrame, &'static str> {
+        let frame = GlobalFrameAllocator.allocate_frame().ok_or("frame alloc failed")?;
+        unsafe {
+            core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096);
+        }
+        self.map_frame(page, frame, flags)?;
+        Ok(frame)
+    }
+
+    /// 既存のフレームをユーザページとしてマップする
+    fn map_frame(&self, page: Page, frame: PhysFrame, flags: PageTableFlags) -> Result<(), &'static str> {
+        // 途中の階層はあとで書き込み可能に変えられるよう、常に WRITABLE にしておく
+        let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        unsafe {
+            self.mapper()
+                .map_to_with_table_flags(page, frame, flags, parent_flags, &mut GlobalFrameAllocator)
+                .map_err(|_| "user page map_to failed")?
+                .flush();
+        }
+        Ok(())
+    }
+
+    /// ユーザページを複製した子プロセス用のアドレス空間を作る
+    /// 書き込み可能なページは親子で共有したまま読み取り専用にし、COW の印を付ける
+    pub fn fork(&self) -> Result<AddressSpace, &'static str> {
+        let child = AddressSpace::new()?;
+
+        let mut result = Ok(());
+        self.for_each_user_page(|page, entry| {
+            if result.is_err() {
+                return;
+            }
+            let Ok(frame) = entry.frame() else {
+                return;
+            };
+
+            let mut flags = entry.flags();
+            if flags.intersects(PageTableFlags::WRITABLE | COW) {
+                flags.remove(PageTableFlags::WRITABLE);
+                flags.insert(COW);
+                entry.set_flags(flags);
+            }
+
+            memory::frame_ref_inc(frame);
+            result = child.map_frame(page, frame, flags);
+            if result.is_err() {
+                memory::frame_ref_dec(frame);
+            }
+        });
+
+        // 親のページを読み取り専用にしたので TLB を捨てる
+        x86_64::instructions::tlb::flush_all();
+
+        match result {
+            Ok(()) => Ok(child),
+            Err(e) => {
+                child.destroy();
+                Err(e)
+            }
+        }
+    }
+
+    /// COW ページへの書き込みで起きたページフォルトを処理する
+    /// addr が COW ページでなければ false を返す
+    pub fn handle_cow_fault(&self, addr: VirtAddr) -> Result<bool, &'static str> {
+        let page = Page::containing_address(addr);
+
+        let (frame, flags) = match self.mapper().translate(page.start_address()) {
+            TranslateResult::Mapped { frame: MappedFrame::Size4KiB(frame), flags, .. } if flags.contains(COW) => (frame, flags),
+            _ => return Ok(false),
+        };
+        let new_flags = (flags | PageTableFlags::WRITABLE) - COW;
+
+        if memory::frame_ref_count(frame) == 1 {
+            // 他に共有しているプロセスがいなければ、そのまま書き込み可能にする
+            unsafe {
+                self.mapper().update_flags(page, new_flags).map_err(|_| "update_flags failed")?.flush();
+            }
+            return Ok(true);
+        }
+
+        // 新しいフレームにページの内容をコピーして付け替える
+        // 確認してからコピーするまでに、共有していた他のプロセスが先にコピーして参照数が 1 になっていれば、そのまま使う
+        let new_frame = GlobalFrameAllocator.allocate_frame().ok_or("frame alloc failed")?;
+        if !memory::copy_shared_frame(frame, new_frame) {
+            memory::free_frame(new_frame);
+            unsafe {
+                self.mapper().update_flags(page, new_flags).map_err(|_| "update_flags failed")?.flush();
+            }
+            return Ok(true);
+        }
+        self.mapper().unmap(page).map_err(|_| "unmap failed")?.1.flush();
+        self.map_frame(page, new_frame, new_flags)?;
+
+        Ok(true)
+    }
+
+    /// アドレス空間の vaddr から data を書き込む
+    /// アドレス空間はロードされていなくてもよい
+    pub fn write_bytes(&self, vaddr: u64, data: &[u8]) -> Result<(), &'static str> {
+        self.copy_in(vaddr, Some(data), data.len())
+    }
+
+    /// アドレス空間の vaddr から len バイトをゼロで埋める
+    pub fn zero_bytes(&self, vaddr: u64, len: usize) -> Result<(), &'static str> {
+        self.copy_in(vaddr, None, len)
+    }
+
+    /// vaddr から len バイトに src を書き込む (None ならゼロで埋める)
+    fn copy_in(&self, vaddr: u64, src: Option<&[u8]>, len: usize) -> Result<(), &'static str> {
+        let mapper = self.mapper();
+        let mut done = 0;
+        while done < len {
+            let addr = VirtAddr::try_new(vaddr + done as u64).map_err(|_| "bad user address")?;
+            let phys = mapper.translate_addr(addr).ok_or("user page not mapped")?;
+            let n = core::cmp::min(len - done, 4096 - usize::from(addr.page_offset()));
+            let dst = memory::phys_to_virt(phys).as_mut_ptr::<u8>();
+            unsafe {
+                match src {
+                    Some(src) => core::ptr::copy_nonoverlapping(src[done..].as_ptr(), dst, n),
+                    None => core::ptr::write_bytes(dst, 0, n),
+                }
+            }
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// ユーザページとユーザ領域のページテーブル、level4 テーブルを解放する
+    /// 他のアドレス空間と共有しているフレームは参照数を減らすだけにする
+    /// ロード中のアドレス空間を破棄してはならない
+    pub fn destroy(self) {
+        let (current, _) = Cr3::read();
+        assert!(current != self.pml4, "destroying the active address space");
+
+        // ユーザページを解放
+        self.for_each_user_page(|_, entry| {
+            if let Ok(frame) = entry.frame() && memory::frame_ref_dec(frame) == 0 {
+                memory::free_frame(frame);
+            }
+            entry.set_unused();
+        });
+
+        // ユーザ領域のページテーブルを解放
+        let p4 = unsafe { &mut *page_table_ptr(self.pml4) };
+        for (i4, e4) in p4.iter_mut().enumerate() {
+            if !is_user_entry(i4) {
+                continue;
+            }
+            let Ok(p3_frame) = e4.frame() else { continue };
+            let p3 = unsafe { &*page_table_ptr(p3_frame) };
+            for e3 in p3.iter() {
+                let Ok(p2_frame) = e3.frame() else { continue };
+                let p2 = unsafe { &*page_table_ptr(p2_frame) };
+                for e2 in p2.iter() {
+                    if let Ok(p1_frame) = e2.frame() {
+                        memory::free_frame(p1_frame);
+                    }
+                }
+                memory::free_frame(p2_frame);
+            }
+            memory::free_frame(p3_frame);
+            e4.set_unused();
+        }
+
+        memory::free_frame(self.pml4);
+    }
+
+    /// マップされているユーザページをすべてたどる
+    fn for_each_user_page(&self, mut f: impl FnMut(Page, &mut PageTableEntry)) {
+        let p4 = unsafe { &mut *page_table_ptr(self.pml4) };
+        for (i4, e4) in p4.iter_mut().enumerate() {
+            if !is_user_entry(i4) {
+                continue;
+            }
+            let Ok(p3_frame) = e4.frame() else { continue };
+            let p3 = unsafe { &mut *page_table_ptr(p3_frame) };
+            for (i3, e3) in p3.iter_mut().enumerate() {
+                let Ok(p2_frame) = e3.frame() else { continue };
+                let p2 = unsafe { &mut *page_table_ptr(p2_frame) };
+                for (i2, e2) in p2.iter_mut().enumerate() {
+                    let Ok(p1_frame) = e2.frame() else { continue };
+                    let p1 = unsafe { &mut *page_table_ptr(p1_frame) };
+                    for (i1, e1) in p1.iter_mut().enumerate() {
+                        if e1.is_unused() {
+                            continue;
+                        }
+                        let page = Page::from_page_table_indices(
+                            PageTableIndex::new(i4 as u16),
+                            PageTableIndex::new(i3 as u16),
+                            PageTableIndex::new(i2 as u16),
+                            PageTableIndex::new(i1 as u16),
+                        );
+                        f(page, e1);
+                    }
+                }
+            }
+        }
+    }
+
+    /// このアドレス空間を CR3 にロードする
+    pub fn activate(&self) {
+        load_pml4(self.pml4);
+    }
+}
+
+/// カーネルの level4 テーブルで、ユーザ領域以外の空いているエントリすべてに level3 テーブルを用意する
+/// プロセスのアドレス空間は作成時にカーネルの level4 エントリをコピーするので、
+/// 以降のカーネルのマッピング (MMIO など) は共有している level3 以下に入り、既存のプロセスからも見える
+/// 共有のフレームアロケータを初期化した直後、プロセスを作る前に呼ぶ
+pub fn init_kernel_tables() -> Result<(), &'static str> {
+    let kernel_table = unsafe { &mut *page_table_ptr(memory::kernel_pml4()) };
+    for (i, entry) in kernel_table.iter_mut().enumerate() {
+        if is_user_entry(i) || !entry.is_unused() {
+            continue;
+        }
+        let frame = GlobalFrameAllocator.allocate_frame().ok_or("frame alloc failed")?;
+        unsafe {
+            core::ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096);
+        }
+        entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    Ok(())
+}
+
+/// level4 テーブルを切り替える
+/// すでにロードされていれば TLB をフラッシュしないよう何もしない
+pub fn load_pml4(pml4: PhysFrame) {
+    let (current, flags) = Cr3::read();
+    if current != pml4 {
+        unsafe {
+            Cr3::write(pml4, flags);
+        }
+    }
+}
+
+fn page_table_ptr(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// level4 テーブルの index 番目のエントリがユーザ領域か
+fn is_user_entry(index: usize) -> bool {
+    let start = usize::from(VirtAddr::new(USER_SPACE_START).p4_index());
+    let end = usize::from(VirtAddr::new(USER_SPACE_END).p4_index());
+    (start..end).contains(&index)
+}
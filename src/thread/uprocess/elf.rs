@@ -0,0 +1,198 @@
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{ Page, PageTableFlags, Translate, mapper::TranslateResult };
+use super::{ AddressSpace, USER_SPACE_START, USER_SPACE_END };
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// プログラムヘッダのタイプ
+const PT_LOAD: u32 = 1;
+
+/// セグメントのパーミッション
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+/// プログラムヘッダ
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// 静的リンクされた ELF64 実行ファイル
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> Elf<'a> {
+    /// ELF ヘッダを検証する
+    /// 不正なヘッダであれば Err を返す
+    pub fn parse(data: &'a [u8]) -> Result<Self, &'static str> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err("ELF: file too small");
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err("ELF: bad magic");
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err("ELF: not a little-endian ELF64 file");
+        }
+        if read_u16(data, 16)? != ET_EXEC {
+            return Err("ELF: not an executable");
+        }
+        if read_u16(data, 18)? != EM_X86_64 {
+            return Err("ELF: not an x86_64 executable");
+        }
+
+        let entry = read_u64(data, 24)?;
+        let phoff = read_u64(data, 32)? as usize;
+        let phentsize = read_u16(data, 54)? as usize;
+        let phnum = read_u16(data, 56)? as usize;
+        if phentsize != PROGRAM_HEADER_SIZE {
+            return Err("ELF: bad program header size");
+        }
+        let ph_end = phnum.checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(phoff))
+            .ok_or("ELF: bad program header table")?;
+        if ph_end > data.len() {
+            return Err("ELF: program header table out of range");
+        }
+
+        let elf = Elf { data, entry, phoff, phnum };
+
+        // セグメントがファイルとユーザ領域に収まっているか確認
+        let mut entry_in_segment = false;
+        for ph in elf.program_headers() {
+            if ph.p_type != PT_LOAD {
+                continue;
+            }
+            if ph.filesz > ph.memsz {
+                return Err("ELF: segment filesz larger than memsz");
+            }
+            let file_end = ph.offset.checked_add(ph.filesz).ok_or("ELF: bad segment offset")?;
+            if file_end > data.len() as u64 {
+                return Err("ELF: segment out of file");
+            }
+            let mem_end = ph.vaddr.checked_add(ph.memsz).ok_or("ELF: bad segment address")?;
+            if ph.vaddr < USER_SPACE_START || mem_end > USER_SPACE_END {
+                return Err("ELF: segment outside user space");
+            }
+            if ph.flags & PF_X != 0 && (ph.vaddr..mem_end).contains(&entry) {
+                entry_in_segment = true;
+            }
+        }
+        if !entry_in_segment {
+            return Err("ELF: entry point is not in an executable segment");
+        }
+
+        Ok(elf)
+    }
+
+    /// エントリポイント
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// プログラムヘッダのイテレータ
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.phnum).map(move |i| {
+            let ph = &self.data[self.phoff + i * PROGRAM_HEADER_SIZE..][..PROGRAM_HEADER_SIZE];
+            ProgramHeader {
+                p_type: u32::from_le_bytes(ph[0..4].try_into().unwrap()),
+                flags: u32::from_le_bytes(ph[4..8].try_into().unwrap()),
+                offset: u64::from_le_bytes(ph[8..16].try_into().unwrap()),
+                vaddr: u64::from_le_bytes(ph[16..24].try_into().unwrap()),
+                filesz: u64::from_le_bytes(ph[32..40].try_into().unwrap()),
+                memsz: u64::from_le_bytes(ph[40..48].try_into().unwrap()),
+            }
+        })
+    }
+
+    /// PT_LOAD セグメントをアドレス空間にマップし、内容をコピーする
+    /// filesz を超える部分 (.bss) はゼロで埋める
+    pub fn load(&self, address_space: &AddressSpace) -> Result<(), &'static str> {
+        for ph in self.program_headers() {
+            if ph.p_type != PT_LOAD || ph.memsz == 0 {
+                continue;
+            }
+
+            let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+            if ph.flags & PF_W != 0 {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if ph.flags & PF_X == 0 {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+
+            let start_page = Page::containing_address(VirtAddr::new(ph.vaddr));
+            let end_page = Page::containing_address(VirtAddr::new(ph.vaddr + ph.memsz - 1));
+            for page in Page::range_inclusive(start_page, end_page) {
+                map_segment_page(address_space, page, flags)?;
+            }
+
+            // ファイルの内容をコピーし、残りをゼロで埋める
+            let file = &self.data[ph.offset as usize..][..ph.filesz as usize];
+            address_space.write_bytes(ph.vaddr, file)?;
+            address_space.zero_bytes(ph.vaddr + ph.filesz, (ph.memsz - ph.filesz) as usize)?;
+        }
+        Ok(())
+    }
+}
+
+/// セグメントのページをマップする
+/// 隣り合うセグメントがページを共有している場合は、両方のパーミッションを合わせる
+fn map_segment_page(address_space: &AddressSpace, page: Page, flags: PageTableFlags) -> Result<(), &'static str> {
+    use x86_64::structures::paging::Mapper;
+
+    let mut mapper = address_space.mapper();
+    match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped { flags: old_flags, .. } => {
+            let mut new_flags = old_flags | (flags & PageTableFlags::WRITABLE);
+            if !flags.contains(PageTableFlags::NO_EXECUTE) {
+                new_flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+            unsafe {
+                mapper.update_flags(page, new_flags).map_err(|_| "ELF: update_flags failed")?.flush();
+            }
+            Ok(())
+        }
+        _ => address_space.map_user_page(page, flags).map(|_| ()),
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16, &'static str> {
+    let bytes = data.get(offset..offset + 2).ok_or("ELF: header out of range")?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, &'static str> {
+    let bytes = data.get(offset..offset + 8).ok_or("ELF: header out of range")?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+#[test_case]
+fn test_reject_bad_magic() {
+    let mut data = [0u8; ELF_HEADER_SIZE];
+    data[0..4].copy_from_slice(b"\x7fBAD");
+    assert!(Elf::parse(&data).is_err());
+}
+
+#[test_case]
+fn test_reject_truncated_header() {
+    let data = [0x7f, b'E', b'L', b'F', ELFCLASS64, ELFDATA2LSB, EV_CURRENT];
+    assert!(Elf::parse(&data).is_err());
+}
@@ -0,0 +1,132 @@
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::{ Page, PageTableFlags };
+use super::{ AddressSpace, PROCESS_TABLE, USER_STACK_TOP, USER_STACK_PAGES };
+use super::elf::Elf;
+use crate::fpu;
+use crate::thread::{ self, THREAD_TABLE };
+
+/// 引数・環境変数の個数の上限
+pub const MAXARG: usize = 32;
+
+/// 初期スタックに積む文字列の合計の上限
+pub const MAX_ARG_SIZE: usize = 4096;
+
+// 補助ベクタの種類
+const AT_NULL: u64 = 0;
+const AT_PAGESZ: u64 = 6;
+const AT_ENTRY: u64 = 9;
+
+/// ロードしたプログラムのイメージ
+pub struct Image {
+    pub address_space: AddressSpace,
+    pub entry: u64,
+    pub user_sp: u64,       // 初期スタックの argc を指す
+}
+
+/// ELF をロードした新しいアドレス空間を作り、argv/envp を積んだ初期スタックを用意する
+/// 失敗したときは作りかけのアドレス空間を解放する
+pub fn load_image(elf: &Elf, argv: &[&[u8]], envp: &[&[u8]]) -> Result<Image, &'static str> {
+    let address_space = AddressSpace::new()?;
+    match setup_address_space(&address_space, elf, argv, envp) {
+        Ok(user_sp) => Ok(Image { address_space, entry: elf.entry(), user_sp }),
+        Err(e) => {
+            address_space.destroy();
+            Err(e)
+        }
+    }
+}
+
+/// 実行中のプロセスのイメージを新しいプログラムに置き換える
+/// 新しいイメージのロードに失敗したときは元のイメージをそのまま残す
+/// 成功したらエントリポイントと初期スタックポインタを返し、スレッド名を name に変える
+pub fn exec(name: &'static str, elf: &Elf, argv: &[&[u8]], envp: &[&[u8]]) -> Result<(u64, u64), &'static str> {
+    let pid = super::current_pid().ok_or("exec from a kernel thread")?;
+    let image = load_image(elf, argv, envp)?;
+
+    // 新しいアドレス空間に切り替えてから、古いアドレス空間を解放する
+    let old = {
+        let mut table = PROCESS_TABLE.lock();
+        match table[pid].as_mut() {
+            Some(process) => core::mem::replace(&mut process.address_space, image.address_space),
+            None => {
+                drop(table);
+                image.address_space.destroy();
+                return Err("no such process");
+            }
+        }
+    };
+    image.address_space.activate();
+    old.destroy();
+
+    // スレッド名を変え、古いプログラムの FPU/SSE/AVX の状態を捨てる
+    if let Some(tid) = thread::current_tid() {
+        let area = {
+            let mut table = THREAD_TABLE.lock();
+            table[tid].name = name;
+            table[tid].context.fpu
+        };
+        if area != 0 {
+            fpu::reset(area);
+        }
+    }
+
+    Ok((image.entry, image.user_sp))
+}
+
+fn setup_address_space(address_space: &AddressSpace, elf: &Elf, argv: &[&[u8]], envp: &[&[u8]]) -> Result<u64, &'static str> {
+    elf.load(address_space)?;
+
+    // ユーザスタック用領域を用意
+    let stack_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::NO_EXECUTE;
+    let stack_start = USER_STACK_TOP - USER_STACK_PAGES * 4096;
+    for i in 0..USER_STACK_PAGES {
+        let page = Page::containing_address(VirtAddr::new(stack_start + i * 4096));
+        address_space.map_user_page(page, stack_flags)?;
+    }
+
+    build_initial_stack(address_space, elf.entry(), argv, envp)
+}
+
+/// System V ABI の初期スタックを作り、スタックポインタを返す
+///
+///   sp -> argc
+///         argv[0] .. argv[argc - 1], NULL
+///         envp[0] .. envp[envc - 1], NULL
+///         auxv (type, value) .. AT_NULL
+///         argv/envp の文字列 (USER_STACK_TOP まで)
+fn build_initial_stack(address_space: &AddressSpace, entry: u64, argv: &[&[u8]], envp: &[&[u8]]) -> Result<u64, &'static str> {
+    if argv.len() + envp.len() > MAXARG {
+        return Err("too many arguments");
+    }
+    let strings_size: usize = argv.iter().chain(envp).map(|s| s.len() + 1).sum();
+    if strings_size > MAX_ARG_SIZE {
+        return Err("arguments too large");
+    }
+
+    // 文字列をスタックの一番上に積む
+    let mut sp = USER_STACK_TOP;
+    let mut push_string = |s: &[u8]| -> Result<u64, &'static str> {
+        sp -= s.len() as u64 + 1;
+        address_space.write_bytes(sp, s)?;
+        address_space.zero_bytes(sp + s.len() as u64, 1)?;
+        Ok(sp)
+    };
+    let argv_ptrs = argv.iter().map(|s| push_string(s)).collect::<Result<Vec<u64>, _>>()?;
+    let envp_ptrs = envp.iter().map(|s| push_string(s)).collect::<Result<Vec<u64>, _>>()?;
+
+    let mut words = Vec::new();
+    words.push(argv.len() as u64);
+    words.extend_from_slice(&argv_ptrs);
+    words.push(0);
+    words.extend_from_slice(&envp_ptrs);
+    words.push(0);
+    words.extend_from_slice(&[AT_PAGESZ, 4096, AT_ENTRY, entry, AT_NULL, 0]);
+
+    // エントリポイントでは sp が 16 バイト境界にそろっている必要がある
+    let sp = (sp - words.len() as u64 * 8) & !0xf;
+    for (i, word) in words.iter().enumerate() {
+        address_space.write_bytes(sp + i as u64 * 8, &word.to_le_bytes())?;
+    }
+    Ok(sp)
+}
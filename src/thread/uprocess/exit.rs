@@ -0,0 +1,132 @@
+use alloc::vec::Vec;
+use crate::{ fpu, memory, scheduler, thread };
+use crate::thread::{ Thread, THREAD_TABLE };
+use super::{ PROCESS_TABLE, ProcessState, INIT_PID, address_space };
+
+/// exit(code) で終了したときの wait ステータス
+pub fn exited_status(code: i32) -> i32 {
+    (code & 0xff) << 8
+}
+
+/// シグナルで終了したときの wait ステータス
+pub fn signaled_status(signal: i32) -> i32 {
+    signal & 0x7f
+}
+
+/// 実行中のプロセスをシグナルで終了させる
+pub fn kill(signal: i32) -> ! {
+    exit(signaled_status(signal));
+}
+
+/// 実行中のプロセスを終了する
+/// ユーザページは直ちに解放し、カーネルスタックとスロットは親が wait したときに解放する
+pub fn exit(status: i32) -> ! {
+    let pid = super::current_pid().expect("exit from a kernel thread");
+    if pid == INIT_PID {
+        panic!("init exiting (status {:#x})", status);
+    }
+
+    // Zombie にしてからスケジューラに戻るまでの間に、親に回収されないよう割り込みを禁止する
+    x86_64::instructions::interrupts::disable();
+
+    let (address_space, wakeup_chans) = {
+        let mut table = PROCESS_TABLE.lock();
+
+        // 子プロセスを init に引き取らせる
+        // すでに終了している子がいれば init を起こして回収させる
+        let mut zombie_adopted = false;
+        for process in table.iter_mut().flatten() {
+            if process.parent == Some(pid) {
+                process.parent = Some(INIT_PID);
+                zombie_adopted |= process.state == ProcessState::Zombie;
+            }
+        }
+
+        let process = table[pid].as_mut().expect("no such process");
+        process.state = ProcessState::Zombie;
+        process.exit_status = status;
+        let address_space = process.address_space;
+
+        let parent_chan = process.parent.map(|parent| wait_chan(&table, parent));
+        let init_chan = zombie_adopted.then(|| wait_chan(&table, INIT_PID));
+        (address_space, [parent_chan, init_chan])
+    };
+
+    // wait している親を起こす
+    for chan in wakeup_chans.into_iter().flatten() {
+        scheduler::wakeup(chan);
+    }
+
+    // カーネルのページテーブルに切り替えてから、ユーザページを解放する
+    address_space::load_pml4(memory::kernel_pml4());
+    address_space.destroy();
+
+    scheduler::exit_from_context();
+}
+
+/// 子プロセスの終了を待つ
+/// target が Some なら指定した子だけを待つ
+/// 終了した子の Process ID とステータスを返す
+/// 待つべき子がいなければ Err、nohang で終了した子がいなければ Ok(None) を返す
+pub fn wait(target: Option<usize>, nohang: bool) -> Result<Option<(usize, i32)>, &'static str> {
+    let pid = super::current_pid().ok_or("wait from a kernel thread")?;
+
+    loop {
+        let mut table = PROCESS_TABLE.lock();
+
+        let mut have_children = false;
+        let mut zombie = None;
+        for process in table.iter().flatten() {
+            if process.parent != Some(pid) || target.is_some_and(|target| target != process.pid) {
+                continue;
+            }
+            have_children = true;
+            if process.state == ProcessState::Zombie {
+                zombie = Some(process.pid);
+                break;
+            }
+        }
+        if !have_children {
+            return Err("no children");
+        }
+
+        // Zombie の子を Process Table から外す
+        if let Some(child) = zombie.and_then(|child| table[child].take()) {
+            drop(table);
+
+            // 子のスレッドが別の CPU でまだスケジューラに戻っていなければ、戻るまで待つ
+            // exit は割り込みを禁止したままスケジューラに戻るので、長くは待たない
+            let tids = child.threads.iter().flatten().copied().collect::<Vec<_>>();
+            for &tid in &tids {
+                while THREAD_TABLE.lock()[tid].on_cpu {
+                    core::hint::spin_loop();
+                }
+            }
+
+            // 子のスレッドのカーネルスタック・FPU の保存領域とスロットを解放する
+            let mut thread_table = THREAD_TABLE.lock();
+            for tid in tids {
+                thread::free_kstack(thread_table[tid].kstack);
+                if thread_table[tid].context.fpu != 0 {
+                    fpu::free_state(thread_table[tid].context.fpu);
+                }
+                thread_table[tid] = Thread::new();
+            }
+            return Ok(Some((child.pid, child.exit_status)));
+        }
+
+        if nohang {
+            return Ok(None);
+        }
+
+        // 子が終了すると exit が起こす
+        let chan = wait_chan(&table, pid);
+        scheduler::sleep(chan, table);
+    }
+}
+
+/// wait で子の終了を待つときのチャネル
+/// 待っているプロセスの Process Table のスロットのアドレスを使う
+fn wait_chan(table: &[Option<super::Process>; super::NPROCESS], pid: usize) -> usize {
+    &table[pid] as *const _ as usize
+}
@@ -0,0 +1,105 @@
+use crate::syscall::{ SyscallFrame, syscall_return };
+use crate::{ fpu, scheduler, time };
+use crate::thread::{ self, Thread, ThreadState, THREAD_TABLE };
+use super::PROCESS_TABLE;
+
+/// fork のエラー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForkError {
+    NoMemory,               // 子スレッドのカーネルスタック・FPU の保存領域を確保できない
+    Failed(&'static str),
+}
+
+impl From<&'static str> for ForkError {
+    fn from(e: &'static str) -> Self {
+        ForkError::Failed(e)
+    }
+}
+
+/// 実行中のプロセスを複製する
+/// 子プロセスのスレッドは syscall からの戻り値 0 でユーザモードに戻る
+/// 親には子の Process ID を返す
+pub fn fork(frame: &SyscallFrame) -> Result<usize, ForkError> {
+    let parent_pid = super::current_pid().ok_or("fork from a kernel thread")?;
+    let parent_space = PROCESS_TABLE.lock()[parent_pid]
+        .as_ref()
+        .ok_or("no such process")?
+        .address_space;
+
+    // 子スレッドのスロットを確保
+    let tid = thread::next_tid().ok_or("Thread table is full")?;
+
+    // カーネルスタックと FPU の保存領域を確保し、スロットに持たせる
+    // 以降の失敗では discard_thread がスロットと一緒に解放する
+    let Ok(kstack_top) = thread::alloc_kstack() else {
+        thread::discard_thread(tid);
+        return Err(ForkError::NoMemory);
+    };
+    THREAD_TABLE.lock()[tid].kstack = kstack_top;
+    let Ok(fpu_area) = fpu::alloc_state() else {
+        thread::discard_thread(tid);
+        return Err(ForkError::NoMemory);
+    };
+    THREAD_TABLE.lock()[tid].context.fpu = fpu_area;
+
+    // ユーザページを COW で共有したアドレス空間を作成
+    // 失敗したら、それまでに確保したものを解放してから戻る
+    let child_pid = match parent_space.fork() {
+        Ok(child_space) => match super::alloc_process(Some(parent_pid), child_space) {
+            Ok(pid) => pid,
+            Err(e) => {
+                child_space.destroy();
+                thread::discard_thread(tid);
+                return Err(e.into());
+            }
+        },
+        Err(e) => {
+            thread::discard_thread(tid);
+            return Err(e.into());
+        }
+    };
+    if let Err(e) = super::add_thread_to_process(child_pid, tid) {
+        thread::discard_thread(tid);
+        super::discard_process(child_pid);
+        return Err(e.into());
+    }
+
+    // 子スレッドのカーネルスタックに、戻り値を 0 にした SyscallFrame を積む
+    let mut child_frame = *frame;
+    child_frame.rax = 0;
+    let frame_ptr = (kstack_top as usize - core::mem::size_of::<SyscallFrame>()) as *mut SyscallFrame;
+    unsafe {
+        frame_ptr.write(child_frame);
+    }
+
+    // 子スレッドを作成
+    // 最初に切り替わったときに syscall_return から SYSRET でユーザモードに戻る
+    // スレッド名・優先度・nice・affinity は親のものを引き継ぐ
+    let inherited = thread::current_tid().map(|tid| {
+        let table = THREAD_TABLE.lock();
+        let parent = &table[tid];
+        (parent.name, parent.priority, parent.nice, parent.affinity)
+    });
+    let mut child = Thread::new();
+    if let Some((name, priority, nice, affinity)) = inherited {
+        child.name = name;
+        child.priority = priority;
+        child.nice = nice;
+        child.affinity = affinity;
+    }
+    child.tid = tid;
+    child.start_ticks = time::ticks();
+    child.state = ThreadState::Runnable;
+    child.kstack = kstack_top;
+    child.pid = Some(child_pid);
+    child.context.rsp = frame_ptr as u64;
+    child.context.rip = syscall_return as *const () as u64;
+    // FPU/SSE/AVX のレジスタは親のものがそのまま残っているので、子の保存領域に写す
+    child.context.fpu = fpu_area;
+    fpu::save(child.context.fpu);
+    THREAD_TABLE.lock()[tid] = child;
+
+    scheduler::ready(tid);
+
+    Ok(child_pid)
+}
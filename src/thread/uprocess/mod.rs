@@ -1,38 +1,72 @@
-use spin::Mutex;
-use x86_64::{ VirtAddr, structures::paging::{ FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB } };
+use x86_64::VirtAddr;
 use lazy_static::lazy_static;
 
-use super::{ STACK_SIZE, THREAD_TABLE, ThreadState };
-
+use super::{ THREAD_TABLE, ThreadState };
+use crate::{ memory, scheduler };
+use crate::libbackend::lock::IrqMutex;
+
+pub mod address_space;
+pub mod elf;
+pub mod exec;
+pub mod exit;
+pub mod fork;
+pub mod programs;
+pub mod signal;
 mod uthread;
 
+pub use address_space::AddressSpace;
+
 /// ユーザコード
+/// ユーザプログラムはこのアドレス以降にリンクする
 pub const USER_CODE_START: u64 = 0x0000_1000_0000_0000;
 
 /// ユーザスタック
 pub const USER_STACK_TOP: u64 = 0x0000_2000_0000_0000;
 pub const USER_STACK_PAGES: u64 = 4;
 
+/// ユーザ領域
+/// level4 テーブルのエントリ単位 (512 GiB) で区切り、この範囲のエントリだけをプロセスごとに持つ
+pub const USER_SPACE_START: u64 = USER_CODE_START;
+pub const USER_SPACE_END: u64 = USER_STACK_TOP;
+
 /// 最大プロセス数
 pub const NPROCESS: usize = 16;
 
 /// 1プロセスあたりの最大スレッド数
 pub const NTHREAD_PER_PROCESS: usize = 8;
 
+/// init プロセスの Process ID
+/// 最初に作成したユーザプロセスが init になり、親が終了したプロセスを引き取る
+pub const INIT_PID: usize = 0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    Zombie,     // 終了したが、親がまだ wait していない
+}
+
 /// Process Control Block (PCB)
 #[derive(Debug, Clone, Copy)]
 pub struct Process {
     pub pid: usize,
     pub threads: [Option<usize>; NTHREAD_PER_PROCESS],
     pub nthread: usize,
+    pub address_space: AddressSpace,    // プロセスのページテーブル
+    pub parent: Option<usize>,          // 親プロセスの Process ID
+    pub state: ProcessState,
+    pub exit_status: i32,               // wait で親に返すステータス
 }
 
 impl Process {
-    pub const fn new() -> Self {
+    pub const fn new(pid: usize, parent: Option<usize>, address_space: AddressSpace) -> Self {
         Process {
-            pid: 0,
+            pid,
             threads: [None; NTHREAD_PER_PROCESS],
             nthread: 0,
+            address_space,
+            parent,
+            state: ProcessState::Running,
+            exit_status: 0,
         }
     }
 
@@ -49,72 +83,107 @@ impl Process {
 
 lazy_static! {
     /// Process Table
-    pub static ref PROCESS_TABLE: Mutex<[Option<Process>; NPROCESS]> = Mutex::new([None; NPROCESS]);
+    /// スケジューラがアドレス空間の切り替えで読むので、ロック中にプリエンプトされないよう IrqMutex で守る
+    pub static ref PROCESS_TABLE: IrqMutex<[Option<Process>; NPROCESS]> = IrqMutex::new([None; NPROCESS]);
 }
 
-pub fn create_user_process(code: &[u8], mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl FrameAllocator<Size4KiB>) -> Result<(), &'static str> {
-    // ユーザページのフラグ
-    let user_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
-
-    // コードページ用領域を用意
-    let code_page = Page::containing_address(VirtAddr::new(USER_CODE_START));
-    let code_frame = frame_allocator.allocate_frame().expect("frame alloc failed");
-    
-    // コードページにユーザコードをコピー
-    unsafe {
-        mapper.map_to(code_page, code_frame, user_flags, frame_allocator).map_err(|_| "code map_to failed")?.flush();
-        core::ptr::copy_nonoverlapping(code.as_ptr(), USER_CODE_START as *mut u8, code.len());
-    }
+/// ELF 実行ファイルからユーザプロセスを作成する
+/// name は最初のスレッドの名前になる
+pub fn create_user_process(name: &'static str, image: &[u8]) -> Result<(), &'static str> {
+    let elf = elf::Elf::parse(image)?;
 
-    // ユーザスタック用領域を用意
-    let stack_start = USER_STACK_TOP - USER_STACK_PAGES * 4096;
-    for i in 0..USER_STACK_PAGES {
-        let page = Page::containing_address(VirtAddr::new(stack_start + i * 4096));
-        let frame = frame_allocator.allocate_frame().ok_or("frame alloc failed")?;
-        unsafe {
-            mapper.map_to(page, frame, user_flags, frame_allocator).map_err(|_| "stack map_to failed")?.flush();
-        }
-    }
+    // プロセス用のアドレス空間を作成し、プログラムをロード
+    // アドレス空間はまだロードされていないので、物理メモリのマップ経由で書き込む
+    let image = exec::load_image(&elf, &[], &[])?;
+
+    // Process Table に追加
+    // 失敗したら、それまでに確保したものを解放してから戻る
+    let pid = alloc_process(None, image.address_space).inspect_err(|_| image.address_space.destroy())?;
 
     // カーネルスタックを作成
-    let kstack = unsafe {
-        let layout = alloc::alloc::Layout::from_size_align(STACK_SIZE, 16).unwrap();
-        alloc::alloc::alloc(layout)
-    };
-    let kstack_top = kstack as u64 + STACK_SIZE as u64;
+    let kstack_top = super::alloc_kstack().inspect_err(|_| discard_process(pid))?;
 
     // init thread を作成
-    let thread = uthread::create_user_thread(kstack_top);
+    let thread = match uthread::create_user_thread(name, kstack_top, pid, image.entry, image.user_sp) {
+        Ok(thread) => thread,
+        Err(e) => {
+            super::free_kstack(kstack_top);
+            discard_process(pid);
+            return Err(e);
+        }
+    };
 
     // Thread Table に追加
     let tid = thread.tid;
-    let mut thread_table = THREAD_TABLE.lock();
-    thread_table[tid] = thread;
+    THREAD_TABLE.lock()[tid] = thread;
 
-    // Process ID を決定
-    let pid = next_pid()?;
+    // プロセスにスレッドを登録
+    if let Err(e) = add_thread_to_process(pid, tid) {
+        super::discard_thread(tid);
+        discard_process(pid);
+        return Err(e);
+    }
+    scheduler::ready(tid);
+    Ok(())
+}
 
-    // Process 構造体を作成
-    let mut process = Process {
-        pid: pid,
-        threads: [None; 8],
-        nthread: 1,
+/// スレッドが属するプロセスのアドレス空間に切り替える
+/// pid が None (カーネルスレッド) ならカーネルのページテーブルに切り替える
+pub fn activate_address_space(pid: Option<usize>) {
+    let pml4 = match pid {
+        Some(pid) => PROCESS_TABLE.lock()[pid]
+            .as_ref()
+            .filter(|process| process.state == ProcessState::Running)
+            .map(|process| process.address_space.pml4)
+            .unwrap_or_else(memory::kernel_pml4),
+        None => memory::kernel_pml4(),
     };
-    process.add_thread(tid)?;
+    address_space::load_pml4(pml4);
+}
 
-    // Process Table に追加
-    let mut process_table = PROCESS_TABLE.lock();
-    process_table[pid] = Some(process);
+/// COW ページへの書き込みによるページフォルトを、実行中のプロセスのアドレス空間で処理する
+/// 処理できれば true を返す
+pub fn handle_cow_fault(addr: VirtAddr) -> bool {
+    let Some(pid) = current_pid() else {
+        return false;
+    };
+    let Some(address_space) = PROCESS_TABLE.lock()[pid].as_ref().map(|process| process.address_space) else {
+        return false;
+    };
+    address_space.handle_cow_fault(addr).unwrap_or(false)
+}
 
-    Ok(())
+/// 実行中のスレッドが属するプロセスの ID
+pub fn current_pid() -> Option<usize> {
+    let tid = super::current_tid()?;
+    THREAD_TABLE.lock()[tid].pid
 }
 
-fn next_pid() -> Result<usize, &'static str> {
-    let table = PROCESS_TABLE.lock();
-    for i in 0..NPROCESS-1 {
-        if table[i].is_none() {
-            return Ok(i);
+/// 空いている Process ID を探し、プロセスを Process Table に登録する
+fn alloc_process(parent: Option<usize>, address_space: AddressSpace) -> Result<usize, &'static str> {
+    let mut table = PROCESS_TABLE.lock();
+    for pid in 0..NPROCESS-1 {
+        if table[pid].is_none() {
+            table[pid] = Some(Process::new(pid, parent, address_space));
+            return Ok(pid);
         }
     }
     Err("Process table is full")
 }
+
+/// 作りかけのプロセスを Process Table から外し、アドレス空間を解放する
+/// スレッドが登録されていれば、先に discard_thread で解放しておく
+fn discard_process(pid: usize) {
+    let process = PROCESS_TABLE.lock()[pid].take();
+    if let Some(process) = process {
+        process.address_space.destroy();
+    }
+}
+
+/// プロセスにスレッドを登録する
+fn add_thread_to_process(pid: usize, tid: usize) -> Result<(), &'static str> {
+    PROCESS_TABLE.lock()[pid]
+        .as_mut()
+        .ok_or("no such process")?
+        .add_thread(tid)
+}
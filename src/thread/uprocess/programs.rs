@@ -0,0 +1,22 @@
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+
+/// カーネルに組み込んだユーザプログラム
+/// ファイルシステムができるまでは、exec のパスはここから探す
+static PROGRAMS: Mutex<BTreeMap<&'static str, &'static [u8]>> = Mutex::new(BTreeMap::new());
+
+/// プログラムを path で登録する
+pub fn register(path: &'static str, image: &'static [u8]) {
+    PROGRAMS.lock().insert(path, image);
+}
+
+/// path に登録された ELF イメージを探し、登録したパスと一緒に返す
+pub fn find(path: &[u8]) -> Option<(&'static str, &'static [u8])> {
+    let path = core::str::from_utf8(path).ok()?;
+    PROGRAMS.lock().get_key_value(path).map(|(&path, &image)| (path, image))
+}
+
+/// パスの最後の要素 (スレッド名に使う)
+pub fn basename(path: &'static str) -> &'static str {
+    path.rsplit('/').next().unwrap_or(path)
+}
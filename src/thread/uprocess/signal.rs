@@ -0,0 +1,27 @@
+/// シグナル番号
+/// 番号は Linux に合わせる
+pub const SIGILL: i32 = 4;
+pub const SIGTRAP: i32 = 5;
+pub const SIGBUS: i32 = 7;
+pub const SIGFPE: i32 = 8;
+pub const SIGKILL: i32 = 9;
+pub const SIGSEGV: i32 = 11;
+
+/// シグナル名
+pub fn name(signal: i32) -> &'static str {
+    match signal {
+        SIGILL => "SIGILL",
+        SIGTRAP => "SIGTRAP",
+        SIGBUS => "SIGBUS",
+        SIGFPE => "SIGFPE",
+        SIGKILL => "SIGKILL",
+        SIGSEGV => "SIGSEGV",
+        _ => "unknown signal",
+    }
+}
+
+#[test_case]
+fn test_signal_name() {
+    assert_eq!(name(SIGSEGV), "SIGSEGV");
+    assert_eq!(name(0), "unknown signal");
+}
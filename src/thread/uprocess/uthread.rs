@@ -1,32 +1,38 @@
-use super::{ THREAD_TABLE, USER_STACK_TOP, USER_CODE_START, ThreadState };
-use crate::{gdt, thread::Thread};
+use super::{ THREAD_TABLE, ThreadState };
+use super::super::discard_thread;
+use crate::{fpu, gdt, time, thread::Thread};
 
-pub fn create_user_thread(kstack_top: u64) -> Thread {
+pub fn create_user_thread(name: &'static str, kstack_top: u64, pid: usize, entry: u64, user_sp: u64) -> Result<Thread, &'static str> {
     // スレッド ID を確保
-    let tid = super::super::next_tid().expect("Thread table is full");
+    let tid = super::super::next_tid().ok_or("Thread table is full")?;
 
     // スレッドテーブルに追加
     let mut thread = Thread::new();
     thread.tid = tid;
+    thread.name = name;
+    thread.start_ticks = time::ticks();
     thread.state = ThreadState::Runnable;
     thread.kstack = kstack_top;
+    thread.pid = Some(pid);
 
     // コンテキストを初期化する
     thread.context.rsp = kstack_top;
     thread.context.rip = ring3_entry_trampoline as u64;
     thread.context.rflags = 0x200;  // IF (Interrupt Flag) を有効化
-    thread.context.cs = gdt::GDT.1.user_code_selector.0 as u64;
-    thread.context.ss = gdt::GDT.1.user_data_selector.0 as u64;
-    thread.context.rsp3 = USER_STACK_TOP;
+    thread.context.cs = gdt::selectors().user_code_selector.0 as u64;
+    thread.context.ss = gdt::selectors().user_data_selector.0 as u64;
+    thread.context.rsp3 = user_sp;
+    thread.context.rip3 = entry;
+    thread.context.fpu = fpu::alloc_state().inspect_err(|_| discard_thread(tid))?;
 
-    thread
+    Ok(thread)
 }
 
 unsafe extern "C" fn ring3_entry_trampoline() -> ! {
     let (cs, ss, rsp3, rip) = {
         let table = THREAD_TABLE.lock();
         let ctx =&table[super::super::current_tid().expect("No running thread")].context;
-        (ctx.cs, ctx.ss, ctx.rsp3, USER_CODE_START)
+        (ctx.cs, ctx.ss, ctx.rsp3, ctx.rip3)
     };
 
     unsafe {
@@ -38,6 +44,8 @@ unsafe extern "C" fn ring3_entry_trampoline() -> ! {
             "push {rflags}",
             "push {cs}",
             "push {rip}",
+            "cli",
+            "swapgs",           // GS ベースをユーザモードのものに戻す
             "iretq",            // switch: cs, ss, rsp, rflags
             inout("ax") ss => _,
             cs = in(reg) cs,
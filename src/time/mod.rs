@@ -0,0 +1,204 @@
+use core::time::Duration;
+use crate::libbackend::lock::IrqMutex;
+use crate::{ println, scheduler };
+use crate::thread::NTHREAD;
+
+pub mod pit;
+pub mod tsc;
+
+/// タイマ割り込みの周波数 (Hz)
+/// スケジューラのタイムスライスもこの周期になる
+pub const TICK_HZ: u64 = 100;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// sleep_ticks で同時に待てるスレッドの数
+/// 1 つのスレッドは 1 つのスロットしか使わないので、スレッド数と同じだけあれば足りる
+const NSLEEPER: usize = NTHREAD;
+
+/// 起動からの時間
+/// タイマ割り込みから更新するので、IrqMutex で守る
+struct Clock {
+    /// 起動からのタイマ割り込みの回数
+    ticks: u64,
+    /// sleep_ticks で待っているスレッドのうち、最も早い期限
+    next_deadline: u64,
+    /// sleep_ticks で待っているスレッドの期限 (None は空きスロット)
+    /// スレッドはスロットごとのチャネルで眠るので、期限を迎えたスレッドだけを起こせる
+    sleepers: [Option<u64>; NSLEEPER],
+    /// 1ティックの長さは period_count / period_hz 秒
+    period_count: u64,
+    period_hz: u64,
+    /// 最後にティックの長さを変えたときのティック数と時刻 (ns)
+    base_ticks: u64,
+    base_nanos: u64,
+}
+
+impl Clock {
+    /// ティック数 ticks の時点の時刻 (ns)
+    fn nanos_at(&self, ticks: u64) -> u64 {
+        let elapsed = (ticks - self.base_ticks) as u128 * self.period_count as u128 * NANOS_PER_SEC as u128
+            / self.period_hz as u128;
+        self.base_nanos + elapsed as u64
+    }
+
+    /// 期限を迎えたスロットを空け、その番号を expired に書いて数を返す
+    /// next_deadline は残ったスロットの最も早い期限にする
+    fn take_expired(&mut self, expired: &mut [usize; NSLEEPER]) -> usize {
+        let mut n = 0;
+        self.next_deadline = u64::MAX;
+        for (slot, sleeper) in self.sleepers.iter_mut().enumerate() {
+            match *sleeper {
+                Some(deadline) if deadline <= self.ticks => {
+                    *sleeper = None;
+                    expired[n] = slot;
+                    n += 1;
+                }
+                Some(deadline) => self.next_deadline = self.next_deadline.min(deadline),
+                None => {}
+            }
+        }
+        n
+    }
+}
+
+/// 最初は BIOS が設定した PIT の周期 (65536 分周、約 18.2 Hz)
+static CLOCK: IrqMutex<Clock> = IrqMutex::new(Clock {
+    ticks: 0,
+    next_deadline: u64::MAX,
+    sleepers: [None; NSLEEPER],
+    period_count: 65536,
+    period_hz: pit::FREQUENCY_HZ,
+    base_ticks: 0,
+    base_nanos: 0,
+});
+
+/// PIT を TICK_HZ に設定し、TSC の周波数を測る
+/// ティックを数え始める前 (割り込みを有効にする前) に呼ぶ
+pub fn init() {
+    let divisor = pit::set_frequency(TICK_HZ);
+    set_tick_period(divisor, pit::FREQUENCY_HZ);
+    tsc::calibrate();
+    if let Some(hz) = tsc::frequency_hz() {
+        println!("TSC: {}.{:03} MHz{}", hz / 1_000_000, hz / 1_000 % 1_000,
+            if tsc::is_invariant() { " (invariant)" } else { "" });
+    }
+}
+
+/// ティックの長さを count / hz 秒にする
+/// タイマ割り込みの発生源を切り替えたときに呼ぶ。それまでの時刻は引き継ぐ
+pub fn set_tick_period(count: u64, hz: u64) {
+    let mut clock = CLOCK.lock();
+    clock.base_nanos = clock.nanos_at(clock.ticks);
+    clock.base_ticks = clock.ticks;
+    clock.period_count = count;
+    clock.period_hz = hz;
+}
+
+/// タイマ割り込みごとに呼ぶ
+/// 期限を迎えた sleep_ticks のスレッドだけを起こす
+pub fn tick() {
+    let mut expired = [0; NSLEEPER];
+    let n = {
+        let mut clock = CLOCK.lock();
+        clock.ticks += 1;
+        if clock.ticks >= clock.next_deadline {
+            clock.take_expired(&mut expired)
+        } else {
+            0
+        }
+    };
+    for &slot in &expired[..n] {
+        scheduler::wakeup(sleep_chan(slot));
+    }
+}
+
+/// 起動からのティック数
+pub fn ticks() -> u64 {
+    CLOCK.lock().ticks
+}
+
+/// 1ティックの長さ (ns)
+pub fn tick_nanos() -> u64 {
+    let clock = CLOCK.lock();
+    clock.period_count * NANOS_PER_SEC / clock.period_hz
+}
+
+/// 起動からの時間 (ns)
+/// 単調増加し、精度はティックの長さ
+pub fn nanos() -> u64 {
+    let clock = CLOCK.lock();
+    clock.nanos_at(clock.ticks)
+}
+
+/// 起動からの時間
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanos())
+}
+
+/// duration を経過させるのに必要なティック数 (切り上げ)
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let (count, hz) = {
+        let clock = CLOCK.lock();
+        (clock.period_count, clock.period_hz)
+    };
+    let ticks = (duration.as_nanos() * hz as u128).div_ceil(count as u128 * NANOS_PER_SEC as u128);
+    ticks.min(u64::MAX as u128) as u64
+}
+
+/// 実行中のスレッドを少なくとも ticks ティックの間 Sleeping にする
+pub fn sleep_ticks(ticks: u64) {
+    if ticks == 0 {
+        return;
+    }
+
+    let mut clock = CLOCK.lock();
+    // 途中のティックから数え始めるので、1ティック余分に待つ
+    let deadline = clock.ticks.saturating_add(ticks).saturating_add(1);
+    let slot = clock.sleepers.iter().position(Option::is_none).expect("too many sleeping threads");
+    clock.sleepers[slot] = Some(deadline);
+    clock.next_deadline = clock.next_deadline.min(deadline);
+
+    // 期限を迎えると tick がスロットを空けて起こす
+    while clock.sleepers[slot].is_some() {
+        scheduler::sleep(sleep_chan(slot), clock);
+        clock = CLOCK.lock();
+    }
+}
+
+/// sleep_ticks のスロット slot で待つときのチャネル
+/// CLOCK の中のアドレスを使うので、他のチャネルと重ならない
+fn sleep_chan(slot: usize) -> usize {
+    &CLOCK as *const _ as usize + slot
+}
+
+#[test_case]
+fn test_duration_to_ticks() {
+    assert_eq!(duration_to_ticks(Duration::ZERO), 0);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(1)), 1);
+    assert_eq!(duration_to_ticks(Duration::from_nanos(tick_nanos())), 1);
+    assert_eq!(duration_to_ticks(Duration::from_secs(1)), TICK_HZ);
+}
+
+#[test_case]
+fn test_take_expired_only_due_sleepers() {
+    let mut clock = Clock {
+        ticks: 10,
+        next_deadline: 5,
+        sleepers: [None; NSLEEPER],
+        period_count: 1,
+        period_hz: TICK_HZ,
+        base_ticks: 0,
+        base_nanos: 0,
+    };
+    clock.sleepers[1] = Some(5);
+    clock.sleepers[3] = Some(20);
+    clock.sleepers[4] = Some(10);
+
+    let mut expired = [0; NSLEEPER];
+    let n = clock.take_expired(&mut expired);
+    assert_eq!(&expired[..n], &[1, 4]);
+    assert_eq!(clock.sleepers[3], Some(20));
+    assert_eq!(clock.next_deadline, 20);
+    assert_eq!(clock.sleepers.iter().flatten().count(), 1);
+}
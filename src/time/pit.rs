@@ -0,0 +1,59 @@
+use x86_64::instructions::port::Port;
+
+/// PIT の入力クロック (Hz)
+pub const FREQUENCY_HZ: u64 = 1_193_182;
+
+/// I/O ポート
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// bit 0: チャネル 2 のゲート, bit 1: スピーカ出力, bit 5: チャネル 2 の出力
+const CHANNEL2_GATE: u16 = 0x61;
+
+/// コマンド: チャネル 0, 下位→上位バイトの順に書く, モード 2 (rate generator), バイナリ
+const CMD_CHANNEL0_RATE: u8 = 0x34;
+/// コマンド: チャネル 2, 下位→上位バイトの順に書く, モード 0 (interrupt on terminal count), バイナリ
+const CMD_CHANNEL2_ONESHOT: u8 = 0xB0;
+
+/// チャネル 0 (IRQ0) を hz に近い周期で割り込ませる
+/// 実際の周期は分周比で決まるので、設定した分周比を返す (周期は分周比 / FREQUENCY_HZ 秒)
+pub fn set_frequency(hz: u64) -> u64 {
+    let divisor = (FREQUENCY_HZ + hz / 2) / hz.max(1);
+    let divisor = divisor.clamp(1, 65536);
+
+    // 65536 は 0 として書く
+    let [low, high, ..] = (divisor as u32).to_le_bytes();
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        Port::<u8>::new(COMMAND).write(CMD_CHANNEL0_RATE);
+        let mut data = Port::<u8>::new(CHANNEL0_DATA);
+        data.write(low);
+        data.write(high);
+    });
+    divisor
+}
+
+/// チャネル 2 で count クロック分だけビジーウェイトする
+/// 割り込みを使わないので、割り込みを禁止したままでも使える
+/// count は 65535 以下
+pub fn busy_wait(count: u16) {
+    unsafe {
+        let mut gate = Port::<u8>::new(CHANNEL2_GATE);
+
+        // ゲートを閉じ、スピーカは鳴らさない
+        let value = gate.read() & !0b11;
+        gate.write(value);
+
+        Port::<u8>::new(COMMAND).write(CMD_CHANNEL2_ONESHOT);
+        let mut data = Port::<u8>::new(CHANNEL2_DATA);
+        let [low, high] = count.to_le_bytes();
+        data.write(low);
+        data.write(high);
+
+        // ゲートを開けるとカウントを始め、0 になると出力が立つ
+        gate.write(value | 0b01);
+        while gate.read() & (1 << 5) == 0 {
+            core::hint::spin_loop();
+        }
+        gate.write(value);
+    }
+}
@@ -0,0 +1,66 @@
+use core::arch::asm;
+use core::sync::atomic::{ AtomicU64, Ordering };
+use super::{ pit, NANOS_PER_SEC };
+
+/// 1回の計測で PIT を待つクロック数 (約 10ms)
+const CALIBRATION_PIT_COUNT: u16 = 11932;
+/// 計測の回数 (最も短く測れたものを使う)
+const CALIBRATION_ROUNDS: usize = 3;
+
+/// TSC の周波数 (Hz)
+/// 0 なら未計測
+static FREQUENCY_HZ: AtomicU64 = AtomicU64::new(0);
+/// 計測したときの TSC の値
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// TSC を読む
+/// 前の命令を追い越して読まないように lfence を置く
+pub fn rdtsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        asm!("lfence", "rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    ((high as u64) << 32) | low as u64
+}
+
+/// PIT のチャネル 2 を基準に TSC の周波数を測る
+/// 割り込みを禁止した状態で呼ぶ
+pub fn calibrate() {
+    let mut min_cycles = u64::MAX;
+    for _ in 0..CALIBRATION_ROUNDS {
+        let start = rdtsc();
+        pit::busy_wait(CALIBRATION_PIT_COUNT);
+        let cycles = rdtsc() - start;
+        min_cycles = min_cycles.min(cycles);
+    }
+
+    let hz = min_cycles as u128 * pit::FREQUENCY_HZ as u128 / CALIBRATION_PIT_COUNT as u128;
+    BASE.store(rdtsc(), Ordering::Relaxed);
+    FREQUENCY_HZ.store(hz as u64, Ordering::Relaxed);
+}
+
+/// TSC の周波数 (Hz)
+/// 計測前なら None
+pub fn frequency_hz() -> Option<u64> {
+    match FREQUENCY_HZ.load(Ordering::Relaxed) {
+        0 => None,
+        hz => Some(hz),
+    }
+}
+
+/// CPU の周波数が変わっても一定の速さで進む TSC (invariant TSC) か
+pub fn is_invariant() -> bool {
+    let max_extended_leaf = core::arch::x86_64::__cpuid(0x8000_0000).eax;
+    if max_extended_leaf < 0x8000_0007 {
+        return false;
+    }
+    core::arch::x86_64::__cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// 計測してからの時間 (ns)
+/// ティックより細かい時刻が必要なときに使う
+pub fn nanos() -> Option<u64> {
+    let hz = frequency_hz()?;
+    let cycles = rdtsc().wrapping_sub(BASE.load(Ordering::Relaxed));
+    Some((cycles as u128 * NANOS_PER_SEC as u128 / hz as u128) as u64)
+}
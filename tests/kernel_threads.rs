@@ -6,9 +6,10 @@
 
 extern crate alloc;
 
+use alloc::{ boxed::Box, vec::Vec };
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use ferrios::println;
+use ferrios::{ exit_qemu, println, serial_println, QemuExitCode };
 use ferrios::thread;
 use ferrios::scheduler;
 
@@ -28,10 +29,15 @@ fn main(boot_info: &'static BootInfo) -> ! {
         BootInfoFrameAllocator::init(&boot_info.memory_map)
     };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // 以降のフレーム確保は共有のアロケータから行う
+    memory::init_frame_allocator(frame_allocator);
+    // FPU は ferrios::init で初期化済み
+    scheduler::init(Box::new(scheduler::priority::Priority::new()));
 
     // カーネルスレッド作成
-    thread::kthread::create_kernel_thread(kernel_thread_0);
-    thread::kthread::create_kernel_thread(kernel_thread_1);
+    thread::kthread::create_kernel_thread("kernel_thread_0", kernel_thread_0).expect("failed to create kernel_thread_0");
+    thread::kthread::create_kernel_thread("kernel_thread_1", kernel_thread_1).expect("failed to create kernel_thread_1");
+    thread::kthread::create_kernel_thread("join_thread", join_thread).expect("failed to create join_thread");
 
     scheduler::scheduler();
 }
@@ -61,6 +67,24 @@ fn kernel_thread_1() -> ! {
         }
     }
 }
+// クロージャで作ったスレッドに値を渡し、join で結果を受け取る
+// すべて join できたら QEMU を成功で終了する
+fn join_thread() -> ! {
+    const NWORKER: u64 = 4;
+    let handles = (0..NWORKER)
+        .map(|n| thread::kthread::spawn("worker", move || (1..=n * 100).sum::<u64>()).expect("spawn failed"))
+        .collect::<Vec<_>>();
+    for (n, handle) in (0..NWORKER).zip(handles) {
+        let sum = handle.join();
+        assert_eq!(sum, n * 100 * (n * 100 + 1) / 2);
+        serial_println!("Worker {} joined: {}", n, sum);
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {
+        thread::sleep_for(core::time::Duration::from_secs(1));
+    }
+}
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
@@ -11,7 +11,7 @@ use ferrios::{ exit_qemu, QemuExitCode, serial_print, serial_println };
 pub extern "C" fn _start() -> ! {
     serial_print!("stack_overflow::stack_overflow...\t");
 
-    ferrios::gdt::init();
+    ferrios::gdt::init(0);
     init_test_idt();
 
     stack_overflow();
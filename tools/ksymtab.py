@@ -0,0 +1,111 @@
+#!/usr/bin/env python3
+"""カーネル ELF の .ksymtab セクションにシンボルテーブルを書き込む
+
+使い方: tools/ksymtab.py <kernel ELF>
+
+.ksymtab は src/symbols.rs で固定サイズの領域として確保してあるので、
+書き込んでもセクションの配置やコードのアドレスは変わらない。
+
+形式 (リトルエンディアン):
+    header:  magic "KSYM", count: u32, strtab_offset: u32, strtab_size: u32
+    entries: addr: u64, size: u64, name_offset: u32, name_len: u32  (addr の昇順)
+    strtab:  デマングル済みの関数名を並べたもの
+"""
+
+import os
+import struct
+import subprocess
+import sys
+
+SECTION = ".ksymtab"
+MAGIC = b"KSYM"
+HEADER = struct.Struct("<4sIII")
+ENTRY = struct.Struct("<QQII")
+# 長すぎる名前 (ジェネリクスの展開など) はこの長さで切る
+MAX_NAME_LEN = 128
+
+
+def find_section(elf, name):
+    """セクションのファイルオフセットとサイズを返す"""
+    if elf[:4] != b"\x7fELF" or elf[4] != 2 or elf[5] != 1:
+        raise SystemExit("not a little-endian ELF64 file")
+    e_shoff, = struct.unpack_from("<Q", elf, 0x28)
+    e_shentsize, e_shnum, e_shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+
+    def header(index):
+        return struct.unpack_from("<IIQQQQIIQQ", elf, e_shoff + index * e_shentsize)
+
+    shstrtab_offset = header(e_shstrndx)[4]
+    for index in range(e_shnum):
+        sh_name, _, _, _, sh_offset, sh_size, *_ = header(index)
+        start = shstrtab_offset + sh_name
+        if elf[start:elf.index(b"\0", start)].decode() == name:
+            return sh_offset, sh_size
+    raise SystemExit(f"section {name} not found")
+
+
+def read_symbols(path):
+    """nm でデマングル済みの関数シンボルを読む"""
+    nm = os.environ.get("NM", "nm")
+    output = subprocess.run(
+        [nm, "--defined-only", "--print-size", "--demangle", path],
+        check=True, capture_output=True, text=True,
+    ).stdout
+
+    symbols = {}
+    for line in output.splitlines():
+        fields = line.split(maxsplit=3)
+        if len(fields) == 4:
+            addr, size, kind, name = fields
+        elif len(fields) == 3:
+            # サイズ情報がないシンボル
+            addr, kind, name = fields
+            size = "0"
+        else:
+            continue
+        if kind not in "tTwW":
+            continue
+        addr = int(addr, 16)
+        # 同じアドレスに複数の名前があれば最初のものを使う
+        symbols.setdefault(addr, (int(size, 16), truncate(name)))
+    return sorted((addr, size, name) for addr, (size, name) in symbols.items())
+
+
+def truncate(name):
+    encoded = name.encode()
+    if len(encoded) <= MAX_NAME_LEN:
+        return encoded
+    # UTF-8 の文字の途中で切らないようにする
+    return encoded[:MAX_NAME_LEN - 3].decode(errors="ignore").encode() + b"..."
+
+
+def build_table(symbols):
+    strtab = bytearray()
+    entries = bytearray()
+    for addr, size, name in symbols:
+        entries += ENTRY.pack(addr, size, len(strtab), len(name))
+        strtab += name
+    strtab_offset = HEADER.size + len(entries)
+    return HEADER.pack(MAGIC, len(symbols), strtab_offset, len(strtab)) + entries + strtab
+
+
+def main():
+    if len(sys.argv) != 2:
+        raise SystemExit(f"usage: {sys.argv[0]} <kernel ELF>")
+    path = sys.argv[1]
+
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+    offset, size = find_section(elf, SECTION)
+
+    table = build_table(read_symbols(path))
+    if len(table) > size:
+        raise SystemExit(f"symbol table ({len(table)} bytes) does not fit in {SECTION} ({size} bytes)")
+
+    elf[offset:offset + size] = table + bytes(size - len(table))
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,6 @@
+#!/bin/sh
+# cargo run / cargo test のランナー
+# シンボルテーブルを書き込んでから bootimage で起動する
+set -e
+python3 "$(dirname "$0")/ksymtab.py" "$1"
+exec bootimage runner "$@"